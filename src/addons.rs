@@ -1,11 +1,15 @@
+mod apply;
 mod crds;
+mod inventory;
+mod manifest;
 
+use crate::watch::ResourceRef;
 use anyhow::Context as _;
 use k8s_openapi::{
     api::{apps::v1 as appsv1, core::v1},
     apimachinery::pkg::apis::meta::v1::ObjectMeta,
 };
-use kube::{api::PatchParams, Api};
+use kube::Api;
 use rand::Rng;
 use std::{collections::BTreeMap, future::Future, pin::Pin};
 
@@ -14,9 +18,56 @@ trait Addon {
 
     fn fix(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>>;
 
-    fn pre_apply(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
+    /// Prepares cluster for applying manifests. Objects should be created
+    /// with `applier`, so that they are only diffed in dry-run mode.
+    fn pre_apply<'a>(
+        &'a self,
+        _applier: &'a mut apply::Applier,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>> {
         Box::pin(async { Ok(()) })
     }
+
+    /// Adjusts object from the addon manifests before it is applied
+    fn transform(&self, _object: &mut apply::DynObject) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Resources which must be healthy when addon works
+    fn workloads(&self) -> Vec<ResourceRef> {
+        Vec::new()
+    }
+
+    /// Addon runs image built from `tool/`
+    fn uses_tool(&self) -> bool {
+        false
+    }
+
+    /// Removes state which is created by hooks rather than manifests.
+    /// Called after manifests are deleted.
+    fn uninstall(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Checks whether any part of the addon is present in the cluster
+    fn is_installed(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>>>> {
+        let name = self.name().to_string();
+        Box::pin(async move {
+            let k = crate::kube().await?;
+            apply::Applier::new(k, false).any_exists(&name).await
+        })
+    }
+}
+
+/// Deletes object, treating missing one as success
+async fn delete_if_exists<K>(api: &Api<K>, name: &str) -> anyhow::Result<()>
+where
+    K: k8s_openapi::Resource + Clone + serde::de::DeserializeOwned + kube::api::Meta,
+{
+    match api.delete(name, &Default::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(err)) if err.code == 404 => Ok(()),
+        Err(err) => Err(err.into()),
+    }
 }
 
 struct Dashboard;
@@ -27,6 +78,15 @@ impl Addon for Dashboard {
     fn fix(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
         Box::pin(async { Ok(()) })
     }
+    fn workloads(&self) -> Vec<ResourceRef> {
+        vec![
+            ResourceRef::new::<appsv1::Deployment>("kubernetes-dashboard", "kubernetes-dashboard"),
+            ResourceRef::new::<appsv1::Deployment>(
+                "kubernetes-dashboard",
+                "dashboard-metrics-scraper",
+            ),
+        ]
+    }
 }
 
 struct Registry;
@@ -37,60 +97,144 @@ impl Addon for Registry {
     fn fix(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
         Box::pin(async move { install_docker_registry().await })
     }
+    fn workloads(&self) -> Vec<ResourceRef> {
+        vec![ResourceRef::new::<appsv1::Deployment>("registry", "registry")]
+    }
+    fn uninstall(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
+        Box::pin(async move {
+            let k = crate::kube().await?;
+            println!("Deleting registry credentials and certificates");
+            let registry_secrets = Api::<v1::Secret>::namespaced(k.clone(), "registry");
+            delete_if_exists(&registry_secrets, "registry-credentials").await?;
+            delete_if_exists(&registry_secrets, "registry-certs").await?;
+            let admission_secrets = Api::<v1::Secret>::namespaced(k, "admission");
+            delete_if_exists(&admission_secrets, "local-registry-credentials-gold").await?;
+            Ok(())
+        })
+    }
 }
 
+/// Namespaces with this label are skipped by the admission webhook
+const ADMISSION_IGNORE_LABEL: &str = "admission.d-k8s.io/ignore";
+
+/// Namespaces created by Kubernetes itself
+const SYSTEM_NAMESPACES: &[&str] = &["kube-system", "kube-public", "kube-node-lease"];
+
 struct Admission;
 impl Addon for Admission {
     fn name(&self) -> &str {
         "admission"
     }
-    fn fix(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
+    fn pre_apply<'a>(
+        &'a self,
+        applier: &'a mut apply::Applier,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>> {
         Box::pin(async move {
+            // webhook fails closed, so it must not intercept pods which
+            // are needed to run the cluster or the webhook itself
+            let mut labels = BTreeMap::new();
+            labels.insert(ADMISSION_IGNORE_LABEL.to_string(), "true".to_string());
+            for ns in SYSTEM_NAMESPACES {
+                let namespace = apply::DynObject {
+                    api_version: "v1".to_string(),
+                    kind: "Namespace".to_string(),
+                    metadata: ObjectMeta {
+                        name: Some(ns.to_string()),
+                        labels: Some(labels.clone()),
+                        ..Default::default()
+                    },
+                    data: Default::default(),
+                };
+                applier.apply(&namespace).await?;
+            }
+            if applier.dry_run() {
+                return Ok(());
+            }
+            // deployment refers to `{{ tool_image }}`, so it must be
+            // pushed before manifests are applied
             println!("Building tool");
             let tool_path = crate::ROOT.join("tool");
             xshell::cmd!("docker build -t d-k8s-tool {tool_path}").run()?;
             println!("Pushing tool");
-
             crate::push_img::push("d-k8s-tool", "tool").await?;
+            Ok(())
+        })
+    }
+    /// Makes API server trust the webhook certificate, which is issued
+    /// by the local CA, without relying on the node trust store, and
+    /// excludes labeled namespaces from the webhooks
+    fn transform(&self, object: &mut apply::DynObject) -> anyhow::Result<()> {
+        if object.kind != "MutatingWebhookConfiguration"
+            && object.kind != "ValidatingWebhookConfiguration"
+        {
+            return Ok(());
+        }
+        let ca_settings = crate::config_defs::CaSettings::load()?;
+        let ca_bundle = base64::encode(xshell::read_file(&ca_settings.certificate)?);
+        let namespace_selector = serde_json::json!({
+            "matchExpressions": [{
+                "key": ADMISSION_IGNORE_LABEL,
+                "operator": "DoesNotExist",
+            }]
+        });
+        let webhooks = object
+            .data
+            .get_mut("webhooks")
+            .and_then(|webhooks| webhooks.as_array_mut())
+            .context("webhooks are missing")?;
+        for webhook in webhooks {
+            let client_config = webhook
+                .get_mut("clientConfig")
+                .and_then(|client_config| client_config.as_object_mut())
+                .context("webhook has no clientConfig")?;
+            client_config.insert("caBundle".to_string(), ca_bundle.clone().into());
+            let webhook = webhook.as_object_mut().context("webhook is not an object")?;
+            webhook.insert("namespaceSelector".to_string(), namespace_selector.clone());
+        }
+        Ok(())
+    }
+    fn fix(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
+        Box::pin(async move {
             println!("Issuing certificates");
-            issue_certs(
-                &["admission-controller-svc.admission.svc"],
-                "admission-webhook",
-                ("admission", "admission-controller-pki"),
+            let k = crate::kube().await?;
+            crate::certs::issue(
+                &k,
+                &crate::certs::CertSpec {
+                    namespace: "admission".to_string(),
+                    secret: "admission-controller-pki".to_string(),
+                    common_name: "admission-webhook".to_string(),
+                    sans: vec!["admission-controller-svc.admission.svc".to_string()],
+                    consumers: vec!["admission-controller".to_string()],
+                },
             )
             .await?;
-            println!("Patching deployment");
-            let k = crate::kube().await?;
-            let image_registry =
-                crate::service_util::resolve_service("registry", "registry").await?;
-            let image = format!("{}/tool", image_registry);
-            let deployments_api = kube::Api::<appsv1::Deployment>::namespaced(k, "admission");
-            let mut deployment = deployments_api.get("admission-controller").await?;
-            {
-                let spec = deployment.spec.as_mut().context("no .spec")?;
-                spec.replicas = Some(1);
-                let pod_spec = spec
-                    .template
-                    .spec
-                    .as_mut()
-                    .context("no .spec.template.spec")?;
-
-                anyhow::ensure!(pod_spec.containers.len() == 1);
-                let container = &mut pod_spec.containers[0];
-                container.image = Some(image);
-            }
-            deployments_api
-                .replace("admission-controller", &Default::default(), &deployment)
-                .await?;
             Ok(())
         })
     }
+    fn workloads(&self) -> Vec<ResourceRef> {
+        vec![ResourceRef::new::<appsv1::Deployment>(
+            "admission",
+            "admission-controller",
+        )]
+    }
+    fn uses_tool(&self) -> bool {
+        true
+    }
+    fn uninstall(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
+        Box::pin(async move {
+            let k = crate::kube().await?;
+            println!("Deleting webhook certificates");
+            let secrets_api = Api::<v1::Secret>::namespaced(k, "admission");
+            delete_if_exists(&secrets_api, "admission-controller-pki").await
+        })
+    }
 }
 
-struct Security;
-impl Addon for Security {
+/// Addon which is only a set of manifests
+struct NoHooks(String);
+impl Addon for NoHooks {
     fn name(&self) -> &str {
-        "security"
+        &self.0
     }
 
     fn fix(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
@@ -98,58 +242,172 @@ impl Addon for Security {
     }
 }
 
-struct Storage;
-impl Addon for Storage {
-    fn name(&self) -> &str {
-        "storage"
+/// Resolves variable which can be used in addon manifests
+async fn template_variable(name: &str) -> anyhow::Result<String> {
+    match name {
+        "public_ip" => crate::vm::vm_ip().await,
+        "registry" => crate::service_util::resolve_service("registry", "registry").await,
+        "tool_image" => {
+            let registry = crate::service_util::resolve_service("registry", "registry").await?;
+            Ok(format!("{}/tool", registry))
+        }
+        "cluster_name" => Ok(crate::cluster_name().to_string()),
+        _ => anyhow::bail!("unknown variable {}", name),
     }
+}
 
-    fn fix(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
-        Box::pin(async { Ok(()) })
+/// Returns Rust side of the addon
+fn hooks(name: &str) -> Box<dyn Addon> {
+    match name {
+        "crds" => Box::new(crds::Crds),
+        "dashboard" => Box::new(Dashboard),
+        "registry" => Box::new(Registry),
+        "admission" => Box::new(Admission),
+        _ => Box::new(NoHooks(name.to_string())),
     }
 }
 
-pub async fn install(only_apply: bool, filter: Option<&[String]>) -> anyhow::Result<()> {
-    crate::configure_kubectl();
-    let mut all_addons: Vec<Box<dyn Addon>> = vec![
-        Box::new(crds::Crds),
-        Box::new(Security),
-        Box::new(Dashboard),
-        Box::new(Registry),
-        Box::new(Admission),
-        Box::new(Storage),
-    ];
-
-    println!("Applying addons");
-    if let Some(filter) = filter {
-        all_addons = std::mem::take(&mut all_addons)
-            .into_iter()
-            .filter(|addon| filter.contains(&addon.name().to_string()))
-            .collect();
+/// Returns workloads of installed addons, paired with addon name
+pub async fn workloads(k: &kube::Client) -> anyhow::Result<Vec<(String, ResourceRef)>> {
+    let manifest = manifest::Manifest::load()?;
+    let recorded = inventory::load(k).await?;
+    let mut workloads = Vec::new();
+    for entry in manifest.addons() {
+        let addon = hooks(&entry.name);
+        // addons installed before the inventory existed are not recorded
+        if !recorded.contains_key(&entry.name) && !addon.is_installed().await? {
+            continue;
+        }
+        for workload in addon.workloads() {
+            workloads.push((entry.name.clone(), workload));
+        }
     }
-    if all_addons.is_empty() {
-        anyhow::bail!("No addons matched the filter");
+    Ok(workloads)
+}
+
+/// Installs addons. With `dry_run`, only shows what would change:
+/// manifests are applied with server-side dry-run and `fix` hooks are
+/// not executed.
+pub async fn install(
+    only_apply: bool,
+    filter: Option<&[String]>,
+    with_deps: bool,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    crate::configure_kubectl();
+    let manifest = manifest::Manifest::load()?;
+    let selected = manifest.select(filter, with_deps)?;
+    let names: Vec<_> = selected.iter().map(|entry| entry.name.as_str()).collect();
+    if dry_run {
+        println!("Comparing addons: {}", names.join(", "));
+    } else {
+        println!("Installing addons: {}", names.join(", "));
     }
 
-    for addon in &all_addons {
+    let k = crate::kube().await?;
+    let mut applier = apply::Applier::new(k.clone(), dry_run);
+    for entry in selected {
+        let addon = hooks(&entry.name);
         println!("------ Preconfiguring addon {} -------", addon.name());
-        addon.pre_apply().await?;
+        addon.pre_apply(&mut applier).await?;
+        println!("------ Applying addon {} -------", addon.name());
+        applier
+            .apply_addon(addon.name(), &|object| addon.transform(object))
+            .await?;
+        if dry_run {
+            continue;
+        }
+        if !only_apply {
+            println!("------ Setting up addon {} ------", addon.name());
+            addon.fix().await?;
+        }
+        let inventory_entry = inventory::new_entry(addon.name(), addon.uses_tool())?;
+        inventory::record(&k, addon.name(), &inventory_entry).await?;
     }
+    Ok(())
+}
 
-    let addons_base_path = crate::ROOT.join("addons");
-    let mut cmd = xshell::cmd!("kubectl apply --recursive");
-    for addon in &all_addons {
-        cmd = cmd.arg("-f").arg(addons_base_path.join(addon.name()));
+/// Deletes addon from the cluster. Unless `force` is set, refuses to
+/// remove addon which is required by other installed addons.
+pub async fn remove(name: &str, force: bool) -> anyhow::Result<()> {
+    let manifest = manifest::Manifest::load()?;
+    manifest
+        .get(name)
+        .with_context(|| format!("unknown addon {}", name))?;
+    let mut blocking = Vec::new();
+    for dependent in manifest.dependents(name) {
+        if hooks(&dependent.name).is_installed().await? {
+            blocking.push(dependent.name.as_str());
+        }
     }
-
-    cmd.run()?;
-    if only_apply {
-        return Ok(());
+    if !blocking.is_empty() {
+        if !force {
+            anyhow::bail!(
+                "addon {} is required by installed addons {}, remove them first or use --force",
+                name,
+                blocking.join(", ")
+            );
+        }
+        println!(
+            "Warning: removing {} which is required by {}",
+            name,
+            blocking.join(", ")
+        );
     }
-    println!("Setting up addons");
-    for addon in &all_addons {
-        println!("------ Setting up addon {} ------", addon.name());
-        addon.fix().await?;
+
+    let addon = hooks(name);
+    println!("Deleting objects of addon {}", name);
+    let k = crate::kube().await?;
+    let mut applier = apply::Applier::new(k.clone(), false);
+    applier.delete_addon(name).await?;
+    println!("Cleaning up addon {}", name);
+    addon.uninstall().await?;
+    inventory::forget(&k, name).await?;
+    println!("Addon {} removed", name);
+    Ok(())
+}
+
+/// Shows installed addons and whether they match local `addons/`
+pub async fn list() -> anyhow::Result<()> {
+    let manifest = manifest::Manifest::load()?;
+    let k = crate::kube().await?;
+    let installed = inventory::load(&k).await?;
+    let local_tool_image = inventory::tool_image_id();
+    println!("{:<12} {:<14} {:<26} HASH", "NAME", "STATUS", "INSTALLED AT");
+    for entry in manifest.addons() {
+        let addon = hooks(&entry.name);
+        let local_hash = inventory::manifests_hash(&entry.name)?;
+        let present = addon.is_installed().await?;
+        let recorded = installed.get(&entry.name);
+        let status = match recorded {
+            None if present => "drifted",
+            None => "not installed",
+            // recorded, but objects were deleted behind our back
+            Some(_) if !present => "drifted",
+            Some(recorded) if recorded.manifests_hash != local_hash => "out of date",
+            Some(recorded)
+                if addon.uses_tool()
+                    && local_tool_image.is_some()
+                    && recorded.tool_image != local_tool_image =>
+            {
+                "out of date"
+            }
+            Some(_) => "up to date",
+        };
+        let (installed_at, hash) = match recorded {
+            Some(recorded) => (
+                recorded.installed_at.as_str(),
+                recorded
+                    .manifests_hash
+                    .get(..12)
+                    .unwrap_or(&recorded.manifests_hash),
+            ),
+            None => ("-", "-"),
+        };
+        println!(
+            "{:<12} {:<14} {:<26} {}",
+            entry.name, status, installed_at, hash
+        );
     }
     Ok(())
 }
@@ -221,7 +479,7 @@ async fn install_docker_registry() -> anyhow::Result<()> {
     crate::configure_kubectl();
     xshell::cmd!("kubectl create --namespace admission secret docker-registry local-registry-credentials-gold --docker-username {username} --docker-password {password} --docker-server {registry_url}").run().ok();
     println!("Creating docker registry certificates");
-    setup_certs().await?;
+    setup_certs(&k).await?;
     println!("Registry url is {}", registry_url);
     println!("Waiting for registry to become ready");
     crate::watch::watch::<k8s_openapi::api::apps::v1::Deployment>(&k, "registry", "registry", 90)
@@ -231,68 +489,17 @@ async fn install_docker_registry() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn issue_certs(sans: &[&str], name: &str, push_to: (&str, &str)) -> anyhow::Result<()> {
-    let csr = serde_json::json! ({
-        "hosts": sans,
-        "key": {
-            "algo": "rsa",
-            "size": 2048,
-        },
-        "names": [
-            {
-                "C": "RU",
-                "L": "Moscow",
-                "O": "mb",
-                "OU": name,
-                "ST": "MOS",
-            }
-        ]
-    });
-    let csr = serde_json::to_string(&csr)?;
-    let csr_path = format!("/tmp/d-k8s-csr-{}.json", name);
-    tokio::fs::write(&csr_path, &csr).await?;
-
-    let _p = xshell::pushd(&*crate::ROOT)?;
-    let ca_settings: crate::config_defs::CaSettings =
-        serde_json::from_slice(&tokio::fs::read("./etc/ca.json").await?)?;
-    let ca_certificate = &ca_settings.certificate;
-    let ca_private_key = &ca_settings.private_key;
-    let certs =
-        xshell::cmd!("cfssl gencert -ca {ca_certificate} -ca-key {ca_private_key} {csr_path}")
-            .read()?;
-    let certs: serde_json::Value = serde_json::from_str(&certs)?;
-    let certs: BTreeMap<_, _> = vec![
-        (
-            "crt".to_string(),
-            certs["cert"].as_str().unwrap().to_string(),
-        ),
-        (
-            "key".to_string(),
-            certs["key"].as_str().unwrap().to_string(),
-        ),
-    ]
-    .into_iter()
-    .collect();
-    let certs_secret = v1::Secret {
-        string_data: Some(certs),
-        metadata: ObjectMeta {
-            name: Some(push_to.1.to_string()),
-            ..Default::default()
-        },
-        ..Default::default()
-    };
-    let certs_secret = serde_json::to_vec(&certs_secret)?;
-    println!("Pushing certificates to k8s");
-    let k = crate::kube().await?;
-    let secrets_api = Api::<v1::Secret>::namespaced(k, push_to.0);
-    secrets_api
-        .patch(push_to.1, &PatchParams::apply("d-k8s"), certs_secret)
-        .await?;
-    Ok(())
-}
-
-async fn setup_certs() -> anyhow::Result<()> {
+async fn setup_certs(k: &kube::Client) -> anyhow::Result<()> {
     let vm_ip = crate::vm::vm_ip().await?;
-    issue_certs(&[&vm_ip], "docker-registry", ("registry", "registry-certs")).await?;
-    Ok(())
+    crate::certs::issue(
+        k,
+        &crate::certs::CertSpec {
+            namespace: "registry".to_string(),
+            secret: "registry-certs".to_string(),
+            common_name: "docker-registry".to_string(),
+            sans: vec![vm_ip],
+            consumers: vec!["registry".to_string()],
+        },
+    )
+    .await
 }
@@ -0,0 +1,610 @@
+//! Applies addon manifests with server-side apply. Objects applied for
+//! each addon are recorded in the cluster (see `inventory`), so that
+//! objects which were removed from manifests can be pruned.
+use anyhow::Context as _;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{APIResource, ObjectMeta};
+use kube::api::{DeleteParams, PatchParams};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+const FIELD_MANAGER: &str = "d-k8s";
+
+/// Finalizer of the tool's copy controller. Objects with it must be
+/// deleted while the controller, which is part of an addon, still runs.
+pub const COPY_CLEANUP_FINALIZER: &str = "util.d-k8s.io/cleanup-copies";
+
+/// How long to wait for the controller to finalize an object
+const FINALIZE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Object of arbitrary kind
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DynObject {
+    #[serde(rename = "apiVersion")]
+    pub api_version: String,
+    pub kind: String,
+    #[serde(default)]
+    pub metadata: ObjectMeta,
+    #[serde(flatten)]
+    pub data: serde_json::Map<String, serde_json::Value>,
+}
+
+impl k8s_openapi::Resource for DynObject {
+    // real values are only known at runtime, `DynamicResource` is used
+    // to build requests instead
+    const API_VERSION: &'static str = "";
+    const GROUP: &'static str = "";
+    const KIND: &'static str = "";
+    const VERSION: &'static str = "";
+}
+
+impl k8s_openapi::Metadata for DynObject {
+    type Ty = ObjectMeta;
+
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn metadata_mut(&mut self) -> &mut ObjectMeta {
+        &mut self.metadata
+    }
+}
+
+/// Identifies object in the cluster
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub struct ObjectRef {
+    pub api_version: String,
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+impl std::fmt::Display for ObjectRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.namespace {
+            Some(ns) => write!(f, "{} {}/{}", self.kind, ns, self.name),
+            None => write!(f, "{} {}", self.kind, self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Created,
+    Configured,
+    Unchanged,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Outcome::Created => "created",
+            Outcome::Configured => "configured",
+            Outcome::Unchanged => "unchanged",
+        })
+    }
+}
+
+/// Objects applied during the last install of an addon, as recorded
+/// by older versions in the local state directory
+#[derive(Deserialize)]
+struct LegacyAppliedObjects {
+    objects: Vec<ObjectRef>,
+}
+
+fn legacy_applied_objects_path(addon: &str) -> PathBuf {
+    crate::state_dir()
+        .join("addons")
+        .join(format!("{}.json", addon))
+}
+
+async fn remove_legacy_applied_objects(addon: &str) -> anyhow::Result<()> {
+    let path = legacy_applied_objects_path(addon);
+    if path.is_file() {
+        tokio::fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+async fn load_applied_objects(k: &kube::Client, addon: &str) -> anyhow::Result<Vec<ObjectRef>> {
+    if let Some(objects) = super::inventory::load_objects(k, addon).await? {
+        return Ok(objects);
+    }
+    let path = legacy_applied_objects_path(addon);
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let data = tokio::fs::read(&path).await?;
+    let legacy: LegacyAppliedObjects = serde_json::from_slice(&data)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    Ok(legacy.objects)
+}
+
+async fn save_applied_objects(
+    k: &kube::Client,
+    addon: &str,
+    objects: &[ObjectRef],
+) -> anyhow::Result<()> {
+    super::inventory::record_objects(k, addon, objects).await?;
+    remove_legacy_applied_objects(addon).await
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+            continue;
+        }
+        let ext = path.extension().and_then(|ext| ext.to_str());
+        if matches!(ext, Some("yaml") | Some("yml") | Some("json")) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Kinds which other objects may need, applied first
+fn apply_priority(kind: &str) -> u8 {
+    match kind {
+        "Namespace" | "CustomResourceDefinition" => 0,
+        "ServiceAccount" | "ClusterRole" | "Role" => 1,
+        _ => 2,
+    }
+}
+
+/// Reads all manifest files of the addon
+fn read_manifests(addon: &str) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let dir = crate::ROOT.join("addons").join(addon);
+    let mut files = Vec::new();
+    collect_files(&dir, &mut files)?;
+    files.sort();
+    let mut manifests = Vec::new();
+    for file in files {
+        let data = xshell::read_file(&file)?;
+        manifests.push((file, data));
+    }
+    Ok(manifests)
+}
+
+fn parse_objects(file: &Path, data: &str, objects: &mut Vec<DynObject>) -> anyhow::Result<()> {
+    for document in serde_yaml::Deserializer::from_str(data) {
+        let value = serde_yaml::Value::deserialize(document)
+            .with_context(|| format!("failed to parse {}", file.display()))?;
+        if value.is_null() {
+            continue;
+        }
+        let object: DynObject = serde_yaml::from_value(value)
+            .with_context(|| format!("invalid object in {}", file.display()))?;
+        if object.metadata.name.is_none() {
+            anyhow::bail!("{} object in {} has no name", object.kind, file.display());
+        }
+        objects.push(object);
+    }
+    Ok(())
+}
+
+/// Serializes object without fields which are maintained by the server
+fn comparable(object: &DynObject) -> serde_json::Value {
+    let mut object = object.clone();
+    let meta = &mut object.metadata;
+    meta.managed_fields = None;
+    meta.resource_version = None;
+    meta.generation = None;
+    meta.creation_timestamp = None;
+    meta.uid = None;
+    meta.self_link = None;
+    serde_json::to_value(&object).expect("object is serializable")
+}
+
+fn print_diff(diff: &json_patch::Patch) {
+    for op in &diff.0 {
+        match op {
+            json_patch::PatchOperation::Add(op) => println!("  + {}: {}", op.path, op.value),
+            json_patch::PatchOperation::Remove(op) => println!("  - {}", op.path),
+            json_patch::PatchOperation::Replace(op) => println!("  ~ {}: {}", op.path, op.value),
+            other => println!(
+                "  {}",
+                serde_json::to_string(other).expect("patch is serializable")
+            ),
+        }
+    }
+}
+
+fn split_api_version(api_version: &str) -> (&str, &str) {
+    match api_version.find('/') {
+        Some(pos) => (&api_version[..pos], &api_version[pos + 1..]),
+        None => ("", api_version),
+    }
+}
+
+fn has_cleanup_finalizer(object: &DynObject) -> bool {
+    object
+        .metadata
+        .finalizers
+        .iter()
+        .flatten()
+        .any(|finalizer| finalizer == COPY_CLEANUP_FINALIZER)
+}
+
+/// Removes copy controller finalizer, so that object can be deleted
+/// without the controller. Copies made for it are left behind.
+pub async fn strip_cleanup_finalizer(
+    api: &kube::Api<DynObject>,
+    object: &DynObject,
+) -> anyhow::Result<()> {
+    if !has_cleanup_finalizer(object) {
+        return Ok(());
+    }
+    let name = object.metadata.name.as_deref().unwrap_or_default();
+    let finalizers: Vec<_> = object
+        .metadata
+        .finalizers
+        .iter()
+        .flatten()
+        .filter(|finalizer| *finalizer != COPY_CLEANUP_FINALIZER)
+        .collect();
+    let patch = serde_json::json!({
+        "metadata": {
+            "finalizers": finalizers,
+            "resourceVersion": object.metadata.resource_version,
+        }
+    });
+    match api
+        .patch(name, &PatchParams::default(), serde_json::to_vec(&patch)?)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(err)) if err.code == 404 => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("failed to remove finalizer from {}", name)),
+    }
+}
+
+pub struct Applier {
+    k: kube::Client,
+    /// Only show what would change, do not modify anything
+    dry_run: bool,
+    /// Discovered resources by apiVersion
+    resources: BTreeMap<String, Vec<APIResource>>,
+    /// Resolved template variables
+    variables: BTreeMap<String, String>,
+}
+
+impl Applier {
+    pub fn new(k: kube::Client, dry_run: bool) -> Applier {
+        Applier {
+            k,
+            dry_run,
+            resources: BTreeMap::new(),
+            variables: BTreeMap::new(),
+        }
+    }
+
+    /// Renders and parses all manifests of the addon. If `strict` is not
+    /// set, variables which can not be resolved are silently rendered as
+    /// empty strings: this is enough to find objects when addon is checked
+    /// or deleted.
+    async fn load_objects(&mut self, addon: &str, strict: bool) -> anyhow::Result<Vec<DynObject>> {
+        let mut objects = Vec::new();
+        for (file, data) in read_manifests(addon)? {
+            for name in crate::template::variables(&data)
+                .with_context(|| format!("invalid template {}", file.display()))?
+            {
+                if self.variables.contains_key(&name) {
+                    continue;
+                }
+                let value = match super::template_variable(&name).await {
+                    Ok(value) => value,
+                    Err(_) if !strict => continue,
+                    Err(err) => {
+                        return Err(err).with_context(|| {
+                            format!("failed to resolve {} for {}", name, file.display())
+                        })
+                    }
+                };
+                self.variables.insert(name, value);
+            }
+            let rendered = if strict {
+                crate::template::render(&data, &self.variables)?
+            } else {
+                let mut variables = self.variables.clone();
+                for name in crate::template::variables(&data)? {
+                    variables.entry(name).or_default();
+                }
+                crate::template::render(&data, &variables)?
+            };
+            parse_objects(&file, &rendered, &mut objects)?;
+        }
+        objects.sort_by_key(|object| apply_priority(&object.kind));
+        Ok(objects)
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    async fn is_namespaced(&mut self, api_version: &str, kind: &str) -> anyhow::Result<bool> {
+        if !self.resources.contains_key(api_version) {
+            let list = if api_version.contains('/') {
+                self.k.list_api_group_resources(api_version).await
+            } else {
+                self.k.list_core_api_resources(api_version).await
+            }
+            .with_context(|| format!("failed to discover resources of {}", api_version))?;
+            self.resources
+                .insert(api_version.to_string(), list.resources);
+        }
+        let resource = self.resources[api_version]
+            .iter()
+            // subresources have names like `deployments/scale`
+            .find(|res| res.kind == kind && !res.name.contains('/'))
+            .with_context(|| format!("API server does not know {} in {}", kind, api_version))?;
+        Ok(resource.namespaced)
+    }
+
+    /// Resolves object scope, filling in default namespace
+    async fn object_ref(&mut self, object: &DynObject) -> anyhow::Result<ObjectRef> {
+        let namespaced = self
+            .is_namespaced(&object.api_version, &object.kind)
+            .await?;
+        let namespace = if namespaced {
+            Some(
+                object
+                    .metadata
+                    .namespace
+                    .clone()
+                    .unwrap_or_else(|| "default".to_string()),
+            )
+        } else {
+            None
+        };
+        Ok(ObjectRef {
+            api_version: object.api_version.clone(),
+            kind: object.kind.clone(),
+            namespace,
+            name: object.metadata.name.clone().context("object has no name")?,
+        })
+    }
+
+    fn api(&self, object: &ObjectRef) -> kube::Api<DynObject> {
+        let (group, version) = split_api_version(&object.api_version);
+        let mut resource = kube::DynamicResource::new(&object.kind)
+            .group(group)
+            .version(version);
+        if let Some(ns) = &object.namespace {
+            resource = resource.within(ns);
+        }
+        resource.into_api(self.k.clone())
+    }
+
+    async fn get(&self, object: &ObjectRef) -> anyhow::Result<Option<DynObject>> {
+        match self.api(object).get(&object.name).await {
+            Ok(existing) => Ok(Some(existing)),
+            Err(kube::Error::Api(err)) if err.code == 404 => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Applies single object, taking ownership of conflicting fields,
+    /// and reports the result. In dry-run mode, also prints what would
+    /// change compared to the live object.
+    pub async fn apply(&mut self, object: &DynObject) -> anyhow::Result<ObjectRef> {
+        let object_ref = self.object_ref(object).await?;
+        let live = self.get(&object_ref).await?;
+        let mut object = object.clone();
+        object.metadata.namespace = object_ref.namespace.clone();
+        let mut params = PatchParams::apply(FIELD_MANAGER).force();
+        params.dry_run = self.dry_run;
+        let res = self
+            .api(&object_ref)
+            .patch(&object_ref.name, &params, serde_json::to_vec(&object)?)
+            .await;
+        let after = match res {
+            Ok(after) => after,
+            // namespace which is created by the same addon does not
+            // exist yet, so the object can not be checked
+            Err(kube::Error::Api(err)) if self.dry_run && live.is_none() && err.code == 404 => {
+                self.report(&object_ref, Outcome::Created);
+                return Ok(object_ref);
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to apply {}", object_ref));
+            }
+        };
+        let live = match live {
+            Some(live) => live,
+            None => {
+                self.report(&object_ref, Outcome::Created);
+                return Ok(object_ref);
+            }
+        };
+        if self.dry_run {
+            // resourceVersion is not bumped by dry-run requests
+            let diff = json_patch::diff(&comparable(&live), &comparable(&after));
+            if diff.0.is_empty() {
+                self.report(&object_ref, Outcome::Unchanged);
+            } else {
+                self.report(&object_ref, Outcome::Configured);
+                print_diff(&diff);
+            }
+        } else if live.metadata.resource_version == after.metadata.resource_version {
+            self.report(&object_ref, Outcome::Unchanged);
+        } else {
+            self.report(&object_ref, Outcome::Configured);
+        }
+        Ok(object_ref)
+    }
+
+    fn report(&self, object_ref: &ObjectRef, outcome: Outcome) {
+        if self.dry_run {
+            println!("{} {} (dry run)", object_ref, outcome);
+        } else {
+            println!("{} {}", object_ref, outcome);
+        }
+    }
+
+    /// Waits until deleted object is finalized by the copy controller.
+    /// If controller does not respond, removes the finalizer.
+    async fn wait_finalized(&self, object: &ObjectRef) -> anyhow::Result<()> {
+        let deadline = tokio::time::Instant::now() + FINALIZE_TIMEOUT;
+        loop {
+            let live = match self.get(object).await? {
+                Some(live) => live,
+                None => return Ok(()),
+            };
+            if tokio::time::Instant::now() >= deadline {
+                println!(
+                    "Warning: {} was not finalized, its copies may be left behind",
+                    object
+                );
+                return strip_cleanup_finalizer(&self.api(object), &live).await;
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        }
+    }
+
+    /// Deletes object if it exists, returns whether it existed
+    pub async fn delete(&mut self, object: &ObjectRef) -> anyhow::Result<bool> {
+        match self
+            .api(object)
+            .delete(&object.name, &DeleteParams::default())
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(err)) if err.code == 404 => Ok(false),
+            Err(err) => Err(err).with_context(|| format!("failed to delete {}", object)),
+        }
+    }
+
+    /// Applies all manifests of the addon, after passing each object
+    /// through `transform`, and prunes objects which were applied last
+    /// time but are no longer in manifests.
+    pub async fn apply_addon(
+        &mut self,
+        addon: &str,
+        transform: &dyn Fn(&mut DynObject) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let mut objects = self.load_objects(addon, true).await?;
+        for object in &mut objects {
+            transform(object).with_context(|| {
+                format!(
+                    "failed to prepare {} {}",
+                    object.kind,
+                    object.metadata.name.as_deref().unwrap_or_default()
+                )
+            })?;
+        }
+        let previous = load_applied_objects(&self.k, addon).await?;
+        let mut applied = Vec::new();
+        let mut unchecked = Vec::new();
+        for object in &objects {
+            let object_ref = match self.apply(object).await {
+                Ok(object_ref) => object_ref,
+                // kind may be provided by CRD which is not created yet
+                Err(err) if self.dry_run => {
+                    println!(
+                        "{} {}: can not check ({:#})",
+                        object.kind,
+                        object.metadata.name.as_deref().unwrap_or_default(),
+                        err
+                    );
+                    unchecked.push((object.kind.as_str(), object.metadata.name.as_deref()));
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            // several files may declare the same object, e.g. namespace
+            if !applied.contains(&object_ref) {
+                applied.push(object_ref);
+            }
+        }
+        for old in previous.iter().rev() {
+            if applied.contains(old) {
+                continue;
+            }
+            if self.dry_run {
+                let skipped = unchecked
+                    .iter()
+                    .any(|(kind, name)| *kind == old.kind && *name == Some(old.name.as_str()));
+                if !skipped && self.get(old).await?.is_some() {
+                    println!("{} pruned (dry run)", old);
+                }
+                continue;
+            }
+            if self.delete(old).await? {
+                println!("{} pruned", old);
+            }
+        }
+        if self.dry_run {
+            return Ok(());
+        }
+        save_applied_objects(&self.k, addon, &applied).await
+    }
+
+    /// Resolves references to objects from addon manifests. Objects of
+    /// kinds unknown to the API server (e.g. when CRD is already deleted)
+    /// can not exist, so they are skipped.
+    async fn manifest_refs(&mut self, addon: &str) -> anyhow::Result<Vec<ObjectRef>> {
+        let mut refs = Vec::new();
+        for object in self.load_objects(addon, false).await? {
+            if let Ok(object_ref) = self.object_ref(&object).await {
+                refs.push(object_ref);
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Checks whether any object of the addon exists
+    pub async fn any_exists(&mut self, addon: &str) -> anyhow::Result<bool> {
+        let mut refs = self.manifest_refs(addon).await?;
+        refs.extend(load_applied_objects(&self.k, addon).await?);
+        for object_ref in &refs {
+            if self.get(object_ref).await?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Deletes all objects of the addon, in reverse order of applying
+    pub async fn delete_addon(&mut self, addon: &str) -> anyhow::Result<()> {
+        let mut refs = self.manifest_refs(addon).await?;
+        for old in load_applied_objects(&self.k, addon).await? {
+            if !refs.contains(&old) {
+                refs.push(old);
+            }
+        }
+        // controller may be deleted together with the objects it
+        // finalizes, so they go first
+        let mut finalized = Vec::new();
+        for object_ref in &refs {
+            let finalizable = match self.get(object_ref).await? {
+                Some(live) => has_cleanup_finalizer(&live),
+                None => false,
+            };
+            if finalizable && self.delete(object_ref).await? {
+                println!("{} deleted", object_ref);
+                finalized.push(object_ref.clone());
+            }
+        }
+        for object_ref in &finalized {
+            self.wait_finalized(object_ref).await?;
+        }
+        for object_ref in refs.iter().rev() {
+            if finalized.contains(object_ref) {
+                continue;
+            }
+            if self.delete(object_ref).await? {
+                println!("{} deleted", object_ref);
+            }
+        }
+        super::inventory::forget_objects(&self.k, addon).await?;
+        remove_legacy_applied_objects(addon).await
+    }
+}
@@ -1,9 +1,14 @@
-use crate::addons::Addon;
+use crate::addons::{
+    apply::{strip_cleanup_finalizer, Applier, DynObject},
+    Addon,
+};
 use anyhow::Context as _;
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use std::future::Future;
 use std::pin::Pin;
 
+const PROPAGATION_CRD: &str = "propagations.util.d-k8s.io";
+
 pub struct Crds;
 
 impl Addon for Crds {
@@ -11,8 +16,11 @@ impl Addon for Crds {
         "crds"
     }
 
-    fn pre_apply(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
-        Box::pin(async {
+    fn pre_apply<'a>(
+        &'a self,
+        applier: &'a mut Applier,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>> {
+        Box::pin(async move {
             println!("Building tool");
             let tool_path = crate::ROOT.join("tool");
             xshell::cmd!("docker build -t d-k8s-tool {tool_path}").run()?;
@@ -20,22 +28,17 @@ impl Addon for Crds {
             let crd = xshell::cmd!("docker run -i --rm --env PRINT=propagation-custom-resource-definition  d-k8s-tool").read()?;
             let crd: CustomResourceDefinition =
                 serde_json::from_str(crd.trim()).context("failed to parse")?;
+            let crd_name = crd.metadata.name.clone().context("CRD has no name")?;
             println!("Pushing CRD to server");
-            let k = crate::kube().await?;
-            let crd_api = kube::Api::<CustomResourceDefinition>::all(k);
-            if crd_api.create(&Default::default(), &crd).await.is_err() {
-                println!("Replacing CRD");
-                let old_crd = crd_api.get(crd.metadata.name.as_ref().unwrap()).await?;
-                let mut crd = crd;
-                crd.metadata.resource_version = old_crd.metadata.resource_version;
-                crd_api
-                    .replace(
-                        crd.metadata.name.as_ref().unwrap(),
-                        &Default::default(),
-                        &crd,
-                    )
-                    .await?;
+            let crd: DynObject = serde_json::from_value(serde_json::to_value(&crd)?)?;
+            applier.apply(&crd).await?;
+            if applier.dry_run() {
+                return Ok(());
             }
+            // Propagation objects of other addons can only be applied
+            // after the CRD is established
+            let k = crate::kube().await?;
+            crate::watch::watch::<CustomResourceDefinition>(&k, "", &crd_name, 60).await?;
             Ok(())
         })
     }
@@ -43,4 +46,44 @@ impl Addon for Crds {
     fn fix(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
         Box::pin(async { Ok(()) })
     }
+
+    fn uses_tool(&self) -> bool {
+        true
+    }
+
+    fn is_installed(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>>>> {
+        Box::pin(async {
+            let k = crate::kube().await?;
+            let crd_api = kube::Api::<CustomResourceDefinition>::all(k);
+            match crd_api.get(PROPAGATION_CRD).await {
+                Ok(_) => Ok(true),
+                Err(kube::Error::Api(err)) if err.code == 404 => Ok(false),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn uninstall(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
+        Box::pin(async {
+            let k = crate::kube().await?;
+            // copy controller may be gone already, remaining Propagations
+            // would keep the CRD from being deleted
+            let propagations_api = kube::DynamicResource::new("Propagation")
+                .group("util.d-k8s.io")
+                .version("v1")
+                .into_api::<DynObject>(k.clone());
+            match propagations_api.list(&Default::default()).await {
+                Ok(propagations) => {
+                    for propagation in propagations {
+                        strip_cleanup_finalizer(&propagations_api, &propagation).await?;
+                    }
+                }
+                Err(kube::Error::Api(err)) if err.code == 404 => (),
+                Err(err) => return Err(err).context("failed to list Propagations"),
+            }
+            println!("Deleting Propagation custom resource definition");
+            let crd_api = kube::Api::<CustomResourceDefinition>::all(k);
+            crate::addons::delete_if_exists(&crd_api, PROPAGATION_CRD).await
+        })
+    }
 }
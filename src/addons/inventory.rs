@@ -0,0 +1,203 @@
+//! Records installed addons in `d-k8s-system/addons` ConfigMap: one key
+//! per addon, holding JSON-encoded `Entry`. Objects applied for each
+//! addon are kept in `d-k8s-system/addon-objects`, so that any checkout
+//! can prune them.
+use super::apply::ObjectRef;
+use anyhow::Context as _;
+use k8s_openapi::{api::core::v1, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+use kube::{api::PatchParams, Api};
+use serde::{Deserialize, Serialize};
+use sha2::Digest as _;
+use std::{collections::BTreeMap, path::Path};
+
+const NAMESPACE: &str = "d-k8s-system";
+const CONFIG_MAP: &str = "addons";
+const OBJECTS_CONFIG_MAP: &str = "addon-objects";
+
+/// Image which is built from `tool/`
+const TOOL_IMAGE: &str = "d-k8s-tool";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    /// SHA-256 of the addon manifests, see `manifests_hash`
+    pub manifests_hash: String,
+    /// ID of the tool image, for addons which run the tool
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_image: Option<String>,
+    /// RFC 3339 timestamp of the install
+    pub installed_at: String,
+}
+
+fn hash_dir(root: &Path, dir: &Path, hasher: &mut sha2::Sha256) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            hash_dir(root, &path, hasher)?;
+            continue;
+        }
+        let relative = path.strip_prefix(root)?.to_string_lossy().into_owned();
+        let data = std::fs::read(&path)?;
+        // lengths make the encoding unambiguous
+        hasher.update((relative.len() as u64).to_le_bytes());
+        hasher.update(relative.as_bytes());
+        hasher.update((data.len() as u64).to_le_bytes());
+        hasher.update(&data);
+    }
+    Ok(())
+}
+
+/// Hashes names and contents of all files in the addon directory
+pub fn manifests_hash(addon: &str) -> anyhow::Result<String> {
+    let dir = crate::ROOT.join("addons").join(addon);
+    let mut hasher = sha2::Sha256::new();
+    hash_dir(&dir, &dir, &mut hasher)
+        .with_context(|| format!("failed to hash manifests of {}", addon))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns ID of the locally built tool image, if there is one
+pub fn tool_image_id() -> Option<String> {
+    let format = "{{.Id}}";
+    let image = TOOL_IMAGE;
+    let id = xshell::cmd!("docker image inspect --format {format} {image}")
+        .read()
+        .ok()?;
+    Some(id.trim().to_string())
+}
+
+pub fn new_entry(addon: &str, uses_tool: bool) -> anyhow::Result<Entry> {
+    Ok(Entry {
+        manifests_hash: manifests_hash(addon)?,
+        tool_image: if uses_tool { tool_image_id() } else { None },
+        installed_at: k8s_openapi::chrono::Utc::now().to_rfc3339(),
+    })
+}
+
+/// Loads all recorded entries
+pub async fn load(k: &kube::Client) -> anyhow::Result<BTreeMap<String, Entry>> {
+    let config_maps = Api::<v1::ConfigMap>::namespaced(k.clone(), NAMESPACE);
+    let config_map = match config_maps.get(CONFIG_MAP).await {
+        Ok(config_map) => config_map,
+        Err(kube::Error::Api(err)) if err.code == 404 => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut entries = BTreeMap::new();
+    for (addon, data) in config_map.data.unwrap_or_default() {
+        let entry = serde_json::from_str(&data)
+            .with_context(|| format!("invalid inventory entry for {}", addon))?;
+        entries.insert(addon, entry);
+    }
+    Ok(entries)
+}
+
+async fn ensure_namespace(k: &kube::Client) -> anyhow::Result<()> {
+    let namespace = v1::Namespace {
+        metadata: ObjectMeta {
+            name: Some(NAMESPACE.to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    Api::<v1::Namespace>::all(k.clone())
+        .patch(
+            NAMESPACE,
+            &PatchParams::apply("d-k8s"),
+            serde_json::to_vec(&namespace)?,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Changes single key of the ConfigMap, `None` removes it
+async fn set(
+    k: &kube::Client,
+    config_map_name: &str,
+    addon: &str,
+    value: Option<String>,
+) -> anyhow::Result<()> {
+    let config_maps = Api::<v1::ConfigMap>::namespaced(k.clone(), NAMESPACE);
+    // merge patch, so that other addons' keys are kept
+    let patch = serde_json::json!({
+        "data": {
+            addon: value,
+        }
+    });
+    match config_maps
+        .patch(config_map_name, &PatchParams::default(), serde_json::to_vec(&patch)?)
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(err)) if err.code == 404 => {
+            let value = match value {
+                Some(value) => value,
+                None => return Ok(()),
+            };
+            ensure_namespace(k).await?;
+            let mut data = BTreeMap::new();
+            data.insert(addon.to_string(), value);
+            let config_map = v1::ConfigMap {
+                metadata: ObjectMeta {
+                    name: Some(config_map_name.to_string()),
+                    ..Default::default()
+                },
+                data: Some(data),
+                ..Default::default()
+            };
+            config_maps
+                .create(&Default::default(), &config_map)
+                .await?;
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub async fn record(k: &kube::Client, addon: &str, entry: &Entry) -> anyhow::Result<()> {
+    set(k, CONFIG_MAP, addon, Some(serde_json::to_string(entry)?))
+        .await
+        .context("failed to record addon in inventory")
+}
+
+pub async fn forget(k: &kube::Client, addon: &str) -> anyhow::Result<()> {
+    set(k, CONFIG_MAP, addon, None)
+        .await
+        .context("failed to remove addon from inventory")
+}
+
+/// Loads objects applied for the addon, `None` if they were never recorded
+pub async fn load_objects(k: &kube::Client, addon: &str) -> anyhow::Result<Option<Vec<ObjectRef>>> {
+    let config_maps = Api::<v1::ConfigMap>::namespaced(k.clone(), NAMESPACE);
+    let config_map = match config_maps.get(OBJECTS_CONFIG_MAP).await {
+        Ok(config_map) => config_map,
+        Err(kube::Error::Api(err)) if err.code == 404 => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    match config_map.data.unwrap_or_default().get(addon) {
+        Some(data) => {
+            let objects = serde_json::from_str(data)
+                .with_context(|| format!("invalid applied objects of {}", addon))?;
+            Ok(Some(objects))
+        }
+        None => Ok(None),
+    }
+}
+
+pub async fn record_objects(
+    k: &kube::Client,
+    addon: &str,
+    objects: &[ObjectRef],
+) -> anyhow::Result<()> {
+    set(k, OBJECTS_CONFIG_MAP, addon, Some(serde_json::to_string(objects)?))
+        .await
+        .context("failed to record applied objects")
+}
+
+pub async fn forget_objects(k: &kube::Client, addon: &str) -> anyhow::Result<()> {
+    set(k, OBJECTS_CONFIG_MAP, addon, None)
+        .await
+        .context("failed to remove applied objects from inventory")
+}
@@ -0,0 +1,135 @@
+//! Addons list and their dependencies, loaded from `addons/manifest.yaml`
+use anyhow::Context as _;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AddonEntry {
+    pub name: String,
+    /// Addons which must be installed before this one
+    #[serde(default)]
+    pub depends: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    addons: Vec<AddonEntry>,
+}
+
+impl Manifest {
+    pub fn load() -> anyhow::Result<Manifest> {
+        let path = crate::ROOT.join("addons/manifest.yaml");
+        let data = xshell::read_file(&path)?;
+        let mut manifest: Manifest =
+            serde_yaml::from_str(&data).context("failed to parse addons/manifest.yaml")?;
+        manifest.sort().context("addons/manifest.yaml is invalid")?;
+        Ok(manifest)
+    }
+
+    /// All addons, dependencies first
+    pub fn addons(&self) -> &[AddonEntry] {
+        &self.addons
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AddonEntry> {
+        self.addons.iter().find(|addon| addon.name == name)
+    }
+
+    /// Returns addons which directly or transitively depend on `name`
+    pub fn dependents(&self, name: &str) -> Vec<&AddonEntry> {
+        let mut affected = BTreeSet::new();
+        affected.insert(name);
+        // addons are sorted, so dependents always come after dependencies
+        for addon in &self.addons {
+            if addon.depends.iter().any(|dep| affected.contains(dep.as_str())) {
+                affected.insert(addon.name.as_str());
+            }
+        }
+        self.addons
+            .iter()
+            .filter(|addon| addon.name != name && affected.contains(addon.name.as_str()))
+            .collect()
+    }
+
+    /// Validates references and orders addons topologically. Among
+    /// addons which are ready to be installed, the one listed first wins.
+    fn sort(&mut self) -> anyhow::Result<()> {
+        let mut seen = BTreeSet::new();
+        for addon in &self.addons {
+            if !seen.insert(addon.name.as_str()) {
+                anyhow::bail!("addon {} is listed twice", addon.name);
+            }
+            if !crate::ROOT.join("addons").join(&addon.name).is_dir() {
+                anyhow::bail!("addon {} has no directory with manifests", addon.name);
+            }
+        }
+        for addon in &self.addons {
+            for dep in &addon.depends {
+                if !seen.contains(dep.as_str()) {
+                    anyhow::bail!("addon {} depends on unknown addon {}", addon.name, dep);
+                }
+            }
+        }
+
+        let mut pending = std::mem::take(&mut self.addons);
+        while !pending.is_empty() {
+            let ready = pending.iter().position(|addon| {
+                addon
+                    .depends
+                    .iter()
+                    .all(|dep| self.addons.iter().any(|done| &done.name == dep))
+            });
+            match ready {
+                Some(pos) => self.addons.push(pending.remove(pos)),
+                None => {
+                    let names: Vec<_> = pending.iter().map(|addon| addon.name.as_str()).collect();
+                    anyhow::bail!("dependency cycle between addons: {}", names.join(", "));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns addons to install, dependencies first. If `filter` is set,
+    /// only listed addons are selected, plus their dependencies unless
+    /// `with_deps` is false.
+    pub fn select(
+        &self,
+        filter: Option<&[String]>,
+        with_deps: bool,
+    ) -> anyhow::Result<Vec<&AddonEntry>> {
+        let filter = match filter {
+            Some(filter) => filter,
+            None => return Ok(self.addons.iter().collect()),
+        };
+        let mut selected = BTreeSet::new();
+        let mut queue = Vec::new();
+        for name in filter {
+            let addon = self
+                .get(name)
+                .with_context(|| format!("unknown addon {}", name))?;
+            queue.push(addon);
+        }
+        while let Some(addon) = queue.pop() {
+            if !selected.insert(addon.name.as_str()) {
+                continue;
+            }
+            if !filter.contains(&addon.name) {
+                println!("Adding addon {} as a dependency", addon.name);
+            }
+            if !with_deps {
+                continue;
+            }
+            for dep in &addon.depends {
+                queue.push(self.get(dep).expect("dependencies were validated on load"));
+            }
+        }
+        Ok(self
+            .addons
+            .iter()
+            .filter(|addon| selected.contains(addon.name.as_str()))
+            .collect())
+    }
+}
@@ -0,0 +1,94 @@
+//! Local CA which signs certificates of cluster services. `k8s ca init`
+//! creates it in `state/ca` and points `etc/ca.json` to it.
+use anyhow::Context as _;
+use std::{
+    fs::{DirBuilder, OpenOptions, Permissions},
+    io::Write as _,
+    os::unix::fs::{DirBuilderExt as _, OpenOptionsExt as _, PermissionsExt as _},
+    path::Path,
+};
+
+const CERTIFICATE_FILE: &str = "ca.pem";
+const PRIVATE_KEY_FILE: &str = "ca-key.pem";
+
+/// Name of the certificate in the system trust store
+const TRUSTED_CERT_PATH: &str = "/usr/local/share/ca-certificates/d-k8s-local-ca.crt";
+
+/// Writes file readable only by the current user
+fn write_private(path: &Path, data: &str) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    // mode is only applied to new files
+    file.set_permissions(Permissions::from_mode(0o600))?;
+    file.write_all(data.as_bytes())?;
+    Ok(())
+}
+
+/// Points `etc/ca.json` to the new CA, keeping other settings
+fn write_settings(certificate: &str, private_key: &str) -> anyhow::Result<()> {
+    let path = crate::config_defs::CaSettings::path();
+    let mut settings = if path.exists() {
+        let data = xshell::read_file(&path)?;
+        serde_json::from_str(&data)
+            .with_context(|| format!("failed to parse {}", path.display()))?
+    } else {
+        serde_json::json!({})
+    };
+    let object = settings
+        .as_object_mut()
+        .with_context(|| format!("{} must contain an object", path.display()))?;
+    object.insert("certificate".to_string(), certificate.into());
+    object.insert("private_key".to_string(), private_key.into());
+    xshell::write_file(&path, serde_json::to_string_pretty(&settings)? + "\n")?;
+    Ok(())
+}
+
+/// Adds CA certificate to trusted ones on this machine (Debian-like systems)
+fn trust(certificate: &Path) -> anyhow::Result<()> {
+    println!("Adding CA to the system trust store");
+    let target = TRUSTED_CERT_PATH;
+    xshell::cmd!("sudo cp {certificate} {target}").run()?;
+    xshell::cmd!("sudo update-ca-certificates").run()?;
+    Ok(())
+}
+
+pub async fn init(trust_locally: bool, force: bool) -> anyhow::Result<()> {
+    if let Ok(settings) = crate::config_defs::CaSettings::load() {
+        if settings.certificate.exists() && !force {
+            anyhow::bail!(
+                "CA already exists at {}, use --force to replace it",
+                settings.certificate.display()
+            );
+        }
+    }
+    let dir = crate::ROOT.join("state/ca");
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("failed to create {}", dir.display()))?;
+    std::fs::set_permissions(&dir, Permissions::from_mode(0o700))?;
+    println!("Generating CA");
+    let ca = crate::pki::generate_ca("d-k8s local CA", crate::pki::KeyAlgorithm::EcdsaP256)?;
+    let certificate = dir.join(CERTIFICATE_FILE);
+    write_private(&dir.join(PRIVATE_KEY_FILE), &ca.private_key)?;
+    xshell::write_file(&certificate, &ca.certificate)?;
+    println!("Updating etc/ca.json");
+    write_settings(
+        &format!("state/ca/{}", CERTIFICATE_FILE),
+        &format!("state/ca/{}", PRIVATE_KEY_FILE),
+    )?;
+    if trust_locally {
+        trust(&certificate)?;
+    }
+    println!(
+        "CA created, it is valid until {}",
+        ca.not_after.to_rfc3339()
+    );
+    Ok(())
+}
@@ -0,0 +1,221 @@
+//! Certificates issued by the local CA for cluster services. Secrets with
+//! such certificates are labeled, and annotations remember how to
+//! reissue them and which Deployments must be restarted afterwards.
+use anyhow::Context as _;
+use k8s_openapi::{
+    api::{apps::v1 as appsv1, core::v1},
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    chrono::{DateTime, Utc},
+};
+use kube::{
+    api::{ListParams, PatchParams},
+    Api,
+};
+use std::collections::BTreeMap;
+
+const MANAGED_LABEL: &str = "d-k8s.io/managed-cert";
+const COMMON_NAME_ANNOTATION: &str = "d-k8s.io/cert-common-name";
+const SANS_ANNOTATION: &str = "d-k8s.io/cert-sans";
+const NOT_AFTER_ANNOTATION: &str = "d-k8s.io/cert-not-after";
+const CONSUMERS_ANNOTATION: &str = "d-k8s.io/cert-consumers";
+
+/// Certificates expiring sooner are reported by `k8s status`
+pub const EXPIRY_WARNING_DAYS: i64 = 30;
+
+/// Certificate stored in a secret as `crt` and `key`
+pub struct CertSpec {
+    pub namespace: String,
+    pub secret: String,
+    pub common_name: String,
+    /// DNS names and IP addresses
+    pub sans: Vec<String>,
+    /// Deployments in the same namespace which mount the secret
+    pub consumers: Vec<String>,
+}
+
+/// Managed certificate found in the cluster
+pub struct ManagedCert {
+    pub spec: CertSpec,
+    pub not_after: Option<DateTime<Utc>>,
+}
+
+impl ManagedCert {
+    pub fn days_left(&self) -> Option<i64> {
+        self.not_after
+            .map(|not_after| (not_after - Utc::now()).num_days())
+    }
+
+    fn from_secret(secret: v1::Secret) -> anyhow::Result<ManagedCert> {
+        let meta = secret.metadata;
+        let annotations = meta.annotations.unwrap_or_default();
+        let get = |key: &str| annotations.get(key).cloned().unwrap_or_default();
+        let split = |value: String| -> Vec<String> {
+            value
+                .split(',')
+                .filter(|item| !item.is_empty())
+                .map(ToString::to_string)
+                .collect()
+        };
+        let not_after = match annotations.get(NOT_AFTER_ANNOTATION) {
+            Some(not_after) => Some(
+                DateTime::parse_from_rfc3339(not_after)
+                    .context("invalid expiry annotation")?
+                    .with_timezone(&Utc),
+            ),
+            None => None,
+        };
+        Ok(ManagedCert {
+            spec: CertSpec {
+                namespace: meta.namespace.context("secret has no namespace")?,
+                secret: meta.name.context("secret has no name")?,
+                common_name: get(COMMON_NAME_ANNOTATION),
+                sans: split(get(SANS_ANNOTATION)),
+                consumers: split(get(CONSUMERS_ANNOTATION)),
+            },
+            not_after,
+        })
+    }
+}
+
+/// Issues certificate with the local CA and stores it in the secret
+pub async fn issue(k: &kube::Client, spec: &CertSpec) -> anyhow::Result<()> {
+    let ca_settings = crate::config_defs::CaSettings::load()?;
+    let ca = crate::pki::Ca::load(&ca_settings)?;
+    let sans: Vec<&str> = spec.sans.iter().map(String::as_str).collect();
+    let issued = crate::pki::issue(
+        &ca,
+        &crate::pki::CertRequest {
+            common_name: &spec.common_name,
+            sans: &sans,
+            algorithm: ca_settings.algorithm,
+            validity_days: ca_settings.validity_days,
+        },
+    )?;
+    let not_after = issued.not_after;
+    let mut certs = BTreeMap::new();
+    certs.insert("crt".to_string(), issued.certificate);
+    certs.insert("key".to_string(), issued.private_key);
+    let mut labels = BTreeMap::new();
+    labels.insert(MANAGED_LABEL.to_string(), "true".to_string());
+    let mut annotations = BTreeMap::new();
+    annotations.insert(
+        COMMON_NAME_ANNOTATION.to_string(),
+        spec.common_name.clone(),
+    );
+    annotations.insert(SANS_ANNOTATION.to_string(), spec.sans.join(","));
+    annotations.insert(NOT_AFTER_ANNOTATION.to_string(), not_after.to_rfc3339());
+    annotations.insert(CONSUMERS_ANNOTATION.to_string(), spec.consumers.join(","));
+    let certs_secret = v1::Secret {
+        string_data: Some(certs),
+        metadata: ObjectMeta {
+            name: Some(spec.secret.clone()),
+            labels: Some(labels),
+            annotations: Some(annotations),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let certs_secret = serde_json::to_vec(&certs_secret)?;
+    println!("Pushing certificates to k8s");
+    let secrets_api = Api::<v1::Secret>::namespaced(k.clone(), &spec.namespace);
+    secrets_api
+        .patch(&spec.secret, &PatchParams::apply("d-k8s"), certs_secret)
+        .await?;
+    Ok(())
+}
+
+pub async fn list_managed(k: &kube::Client) -> anyhow::Result<Vec<ManagedCert>> {
+    let secrets_api = Api::<v1::Secret>::all(k.clone());
+    let secrets = secrets_api
+        .list(&ListParams::default().labels(MANAGED_LABEL))
+        .await?;
+    secrets
+        .into_iter()
+        .map(ManagedCert::from_secret)
+        .collect()
+}
+
+pub async fn status() -> anyhow::Result<()> {
+    let k = crate::kube().await?;
+    let certs = list_managed(&k).await?;
+    if certs.is_empty() {
+        println!("No managed certificates found");
+        return Ok(());
+    }
+    println!(
+        "{:<40} {:<27} {:<10} SANS",
+        "SECRET", "EXPIRES", "DAYS LEFT"
+    );
+    for cert in &certs {
+        let (expires, days_left) = match (cert.not_after, cert.days_left()) {
+            (Some(not_after), Some(days_left)) => (not_after.to_rfc3339(), days_left.to_string()),
+            _ => ("unknown".to_string(), "-".to_string()),
+        };
+        println!(
+            "{:<40} {:<27} {:<10} {}",
+            format!("{}/{}", cert.spec.namespace, cert.spec.secret),
+            expires,
+            days_left,
+            cert.spec.sans.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Triggers rolling restart, same as `kubectl rollout restart`
+async fn restart_deployment(k: &kube::Client, ns: &str, name: &str) -> anyhow::Result<()> {
+    let deployments_api = Api::<appsv1::Deployment>::namespaced(k.clone(), ns);
+    let patch = serde_json::json!({
+        "spec": {
+            "template": {
+                "metadata": {
+                    "annotations": {
+                        "kubectl.kubernetes.io/restartedAt": Utc::now().to_rfc3339(),
+                    }
+                }
+            }
+        }
+    });
+    deployments_api
+        .patch(name, &PatchParams::default(), serde_json::to_vec(&patch)?)
+        .await
+        .with_context(|| format!("failed to restart deployment {}/{}", ns, name))?;
+    Ok(())
+}
+
+/// Reissues certificates: either all of them or one, identified as
+/// `secret` or `namespace/secret`.
+pub async fn renew(name: Option<&str>, all: bool) -> anyhow::Result<()> {
+    let k = crate::kube().await?;
+    let certs = list_managed(&k).await?;
+    let selected: Vec<&ManagedCert> = match (name, all) {
+        (None, true) => certs.iter().collect(),
+        (Some(name), false) => {
+            let matches: Vec<_> = certs
+                .iter()
+                .filter(|cert| {
+                    cert.spec.secret == name
+                        || format!("{}/{}", cert.spec.namespace, cert.spec.secret) == name
+                })
+                .collect();
+            match matches.len() {
+                0 => anyhow::bail!("managed certificate {} not found", name),
+                1 => matches,
+                _ => anyhow::bail!("{} is ambiguous, use namespace/name", name),
+            }
+        }
+        _ => anyhow::bail!("specify either certificate name or --all"),
+    };
+    for cert in selected {
+        println!(
+            "Renewing certificate {}/{}",
+            cert.spec.namespace, cert.spec.secret
+        );
+        issue(&k, &cert.spec).await?;
+        for consumer in &cert.spec.consumers {
+            println!("Restarting deployment {}/{}", cert.spec.namespace, consumer);
+            restart_deployment(&k, &cert.spec.namespace, consumer).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! Several clusters can live in one project. Each of them keeps its state
+//! in `state/<cluster>/`, and the one used by default is recorded in
+//! `state/default-cluster`.
+use crate::vm::{LegacyVmState, VmConfig, VmState};
+use anyhow::Context as _;
+use std::path::PathBuf;
+
+const FALLBACK_CLUSTER: &str = "k8s";
+
+fn default_cluster_path() -> PathBuf {
+    crate::ROOT.join("state/default-cluster")
+}
+
+pub fn state_dir(cluster: &str) -> PathBuf {
+    crate::ROOT.join("state").join(cluster)
+}
+
+/// Moves state written by versions without cluster support (`state/vm.json`
+/// and `state/kubeconfig`) into the directory of the fallback cluster.
+pub fn migrate_legacy_state() -> anyhow::Result<()> {
+    let state_root = crate::ROOT.join("state");
+    let legacy_vm = state_root.join("vm.json");
+    if !legacy_vm.is_file() {
+        return Ok(());
+    }
+    let target = state_dir(FALLBACK_CLUSTER);
+    if target.join("vm.json").exists() {
+        anyhow::bail!(
+            "both {} and {} exist, remove one of them",
+            legacy_vm.display(),
+            target.join("vm.json").display()
+        );
+    }
+    println!("Moving legacy cluster state to {}", target.display());
+    let legacy: LegacyVmState = serde_json::from_slice(&std::fs::read(&legacy_vm)?)
+        .with_context(|| format!("failed to parse {}", legacy_vm.display()))?;
+    let state = VmState::from(legacy);
+    std::fs::create_dir_all(&target)?;
+    let legacy_kubeconfig = state_root.join("kubeconfig");
+    if legacy_kubeconfig.is_file() {
+        std::fs::rename(&legacy_kubeconfig, target.join("kubeconfig"))?;
+    }
+    std::fs::write(target.join("vm.json"), serde_json::to_string(&state)?)?;
+    std::fs::remove_file(&legacy_vm)?;
+    Ok(())
+}
+
+pub fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name.len() > 40 {
+        anyhow::bail!("cluster name must be 1 to 40 characters long");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        anyhow::bail!(
+            "invalid cluster name {:?}: only lowercase letters, digits and '-' are allowed",
+            name
+        );
+    }
+    if name.starts_with('-') || name.ends_with('-') {
+        anyhow::bail!(
+            "invalid cluster name {:?}: must not start or end with '-'",
+            name
+        );
+    }
+    Ok(())
+}
+
+pub fn default_cluster() -> anyhow::Result<String> {
+    let path = default_cluster_path();
+    if !path.is_file() {
+        return Ok(FALLBACK_CLUSTER.to_string());
+    }
+    let name = xshell::read_file(&path)?;
+    Ok(name.trim().to_string())
+}
+
+pub async fn set_default(name: &str) -> anyhow::Result<()> {
+    validate_name(name)?;
+    if !state_dir(name).join("vm.json").is_file() {
+        println!("Warning: cluster {} is not created yet", name);
+    }
+    tokio::fs::create_dir_all(crate::ROOT.join("state")).await?;
+    tokio::fs::write(default_cluster_path(), format!("{}\n", name)).await?;
+    println!("Default cluster is now {}", name);
+    Ok(())
+}
+
+/// Clusters which have saved VM state
+fn known_clusters() -> anyhow::Result<Vec<String>> {
+    let state_root = crate::ROOT.join("state");
+    let mut names = Vec::new();
+    if !state_root.is_dir() {
+        return Ok(names);
+    }
+    for entry in std::fs::read_dir(&state_root).context("failed to list state directory")? {
+        let entry = entry?;
+        if !entry.path().join("vm.json").is_file() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub async fn list() -> anyhow::Result<()> {
+    let current = crate::cluster_name();
+    let names = known_clusters()?;
+    if names.is_empty() {
+        println!("No clusters created yet");
+        return Ok(());
+    }
+    println!("  {:<20} {:<16} {:<6} VM", "NAME", "CONTROL PLANE", "NODES");
+    for name in names {
+        let marker = if name == current { "*" } else { " " };
+        let state = VmState::load_cluster(&name).await;
+        let (ip, nodes) = match &state {
+            Ok(state) => (
+                state
+                    .control_plane()
+                    .map(|node| node.ip.clone())
+                    .unwrap_or_else(|_| "-".to_string()),
+                state.nodes.len().to_string(),
+            ),
+            Err(_) => ("?".to_string(), "?".to_string()),
+        };
+        let vm = match VmConfig::load(&name).and_then(|config| config.vm_exists()) {
+            Ok(true) => "exists".to_string(),
+            Ok(false) => "missing".to_string(),
+            Err(err) => format!("unknown ({:#})", err),
+        };
+        println!("{} {:<20} {:<16} {:<6} {}", marker, name, ip, nodes, vm);
+    }
+    Ok(())
+}
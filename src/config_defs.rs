@@ -1,6 +1,61 @@
-use std::path::PathBuf;
-#[derive(serde::Deserialize)]
+use anyhow::Context as _;
+use serde::{Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
 pub struct CaSettings {
+    /// PKCS#8 private key of the CA
+    #[serde(deserialize_with = "deserialize_path")]
     pub private_key: PathBuf,
+    #[serde(deserialize_with = "deserialize_path")]
     pub certificate: PathBuf,
+    /// Key algorithm of issued certificates
+    #[serde(default)]
+    pub algorithm: crate::pki::KeyAlgorithm,
+    /// How long issued certificates are valid
+    #[serde(default = "CaSettings::default_validity_days")]
+    pub validity_days: u32,
+}
+
+impl CaSettings {
+    fn default_validity_days() -> u32 {
+        365
+    }
+
+    pub fn path() -> PathBuf {
+        crate::ROOT.join("etc/ca.json")
+    }
+
+    /// Loads `etc/ca.json`. Relative paths in it are relative to the
+    /// project root.
+    pub fn load() -> anyhow::Result<CaSettings> {
+        let path = Self::path();
+        if !path.exists() {
+            anyhow::bail!(
+                "{} not found, create local CA with `k8s ca init`",
+                path.display()
+            );
+        }
+        let data = xshell::read_file(path)?;
+        let mut settings: CaSettings =
+            serde_json::from_str(&data).context("failed to parse etc/ca.json")?;
+        settings.private_key = crate::ROOT.join(&settings.private_key);
+        settings.certificate = crate::ROOT.join(&settings.certificate);
+        Ok(settings)
+    }
+}
+
+/// Replaces leading `~` with current user's home directory
+pub fn expand_tilde(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Deserializes path, expanding `~`.
+/// Use it for all paths in configuration files.
+pub fn deserialize_path<'de, D: Deserializer<'de>>(de: D) -> Result<PathBuf, D::Error> {
+    let path = PathBuf::deserialize(de)?;
+    Ok(expand_tilde(&path))
 }
@@ -1,17 +1,91 @@
 mod addons;
+mod ca;
+mod certs;
+mod clusters;
 mod config_defs;
 mod dashboard;
+mod pki;
 mod push_img;
 mod service_util;
+mod status;
 mod tasks;
+mod template;
 mod vm;
 mod watch;
 
+use anyhow::Context as _;
 use clap::Clap;
-use once_cell::sync::Lazy;
-use std::path::PathBuf;
+use once_cell::sync::{Lazy, OnceCell};
+use std::path::{Path, PathBuf};
 
-const ROOT: Lazy<PathBuf> = Lazy::new(|| "/home/mb/projects/k8s".into());
+static ROOT_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+/// Project directory, containing `etc/`, `state/` and `addons/`
+static ROOT: Lazy<&'static Path> = Lazy::new(|| {
+    ROOT_DIR
+        .get()
+        .expect("project root is not resolved yet")
+        .as_path()
+});
+
+/// Resolves project root: explicit path (from `--root` or `D_K8S_ROOT`)
+/// wins, otherwise the closest ancestor of the current directory
+/// which contains `etc/vm.json` is used.
+fn init_root(explicit: Option<PathBuf>) -> anyhow::Result<()> {
+    let root = match explicit {
+        Some(root) => {
+            let root = config_defs::expand_tilde(&root);
+            if !root.join("etc/vm.json").is_file() {
+                anyhow::bail!("{} does not contain etc/vm.json", root.display());
+            }
+            root
+        }
+        None => {
+            let cwd = std::env::current_dir().context("failed to get current directory")?;
+            cwd.ancestors()
+                .find(|dir| dir.join("etc/vm.json").is_file())
+                .with_context(|| {
+                    format!(
+                        "no etc/vm.json found in {} or its parents, use --root to point to the project",
+                        cwd.display()
+                    )
+                })?
+                .to_path_buf()
+        }
+    };
+    let root = root.canonicalize()?;
+    ROOT_DIR
+        .set(root)
+        .map_err(|_| anyhow::anyhow!("project root is already resolved"))
+}
+
+static CLUSTER: OnceCell<String> = OnceCell::new();
+
+/// Resolves cluster to work with: explicit name (from `--cluster` or
+/// `D_K8S_CLUSTER`) wins, then the default set by `k8s clusters use`.
+fn init_cluster(explicit: Option<String>) -> anyhow::Result<()> {
+    let name = match explicit {
+        Some(name) => name,
+        None => clusters::default_cluster()?,
+    };
+    clusters::validate_name(&name)?;
+    CLUSTER
+        .set(name)
+        .map_err(|_| anyhow::anyhow!("cluster is already resolved"))
+}
+
+fn cluster_name() -> &'static str {
+    CLUSTER.get().expect("cluster is not resolved yet")
+}
+
+/// Directory with state of the current cluster
+fn state_dir() -> PathBuf {
+    clusters::state_dir(cluster_name())
+}
+
+fn kubeconfig_path() -> PathBuf {
+    state_dir().join("kubeconfig")
+}
 
 #[derive(Debug, Clap)]
 struct ArgsK {
@@ -19,12 +93,44 @@ struct ArgsK {
     args: Vec<String>,
 }
 
+#[derive(Debug, Clap)]
+struct ArgsAddonsRemove {
+    name: String,
+    /// Remove addon even if other installed addons depend on it
+    #[clap(long)]
+    force: bool,
+}
+
+#[derive(Debug, Clap)]
+struct ArgsAddonsDiff {
+    #[clap(long, short)]
+    filter: Vec<String>,
+    /// Do not compare dependencies of addons selected with --filter
+    #[clap(long)]
+    no_deps: bool,
+}
+
+#[derive(Clap, Debug)]
+enum AddonsCommand {
+    /// Deletes addon and state created by it
+    Remove(ArgsAddonsRemove),
+    /// Shows what installing addons would change, without changing anything
+    Diff(ArgsAddonsDiff),
+    /// Shows installed addons and whether they are up to date
+    List,
+}
+
 #[derive(Debug, Clap)]
 struct ArgsAddons {
+    #[clap(subcommand)]
+    command: Option<AddonsCommand>,
     #[clap(long)]
     only_apply: bool,
     #[clap(long, short)]
     filter: Vec<String>,
+    /// Do not install dependencies of addons selected with --filter
+    #[clap(long)]
+    no_deps: bool,
 }
 
 #[derive(Debug, Clap)]
@@ -39,34 +145,160 @@ struct ArgsAddUser {
     name: String,
 }
 
+#[derive(Debug, Clap)]
+struct ArgsUp {
+    /// Number of worker nodes in addition to control plane
+    #[clap(long, default_value = "0")]
+    workers: usize,
+    /// Continue provisioning of existing VMs instead of recreating them
+    #[clap(long)]
+    resume: bool,
+}
+
+#[derive(Debug, Clap)]
+struct ArgsNodeRemove {
+    /// VM name of the node, as shown in `k8s clusters list`
+    name: String,
+}
+
+#[derive(Clap, Debug)]
+enum NodeCommand {
+    /// Creates new worker node and joins it to the cluster
+    Add,
+    /// Drains worker node and deletes its VM
+    Remove(ArgsNodeRemove),
+}
+
+#[derive(Debug, Clap)]
+struct ArgsNode {
+    #[clap(subcommand)]
+    command: NodeCommand,
+}
+
+#[derive(Debug, Clap)]
+struct ArgsClustersUse {
+    name: String,
+}
+
+#[derive(Clap, Debug)]
+enum ClustersCommand {
+    /// Shows all clusters which have state in this project
+    List,
+    /// Makes cluster default for commands without --cluster
+    Use(ArgsClustersUse),
+}
+
+#[derive(Debug, Clap)]
+struct ArgsClusters {
+    #[clap(subcommand)]
+    command: ClustersCommand,
+}
+
+#[derive(Debug, Clap)]
+struct ArgsCaInit {
+    /// Also add the CA to the trust store of this machine
+    #[clap(long)]
+    trust: bool,
+    /// Replace existing CA. Certificates issued by it stop being trusted.
+    #[clap(long)]
+    force: bool,
+}
+
+#[derive(Clap, Debug)]
+enum CaCommand {
+    /// Generates local CA and writes etc/ca.json
+    Init(ArgsCaInit),
+}
+
+#[derive(Debug, Clap)]
+struct ArgsCa {
+    #[clap(subcommand)]
+    command: CaCommand,
+}
+
+#[derive(Debug, Clap)]
+struct ArgsCertsRenew {
+    /// Secret name, optionally prefixed with `namespace/`
+    name: Option<String>,
+    /// Renew all managed certificates
+    #[clap(long)]
+    all: bool,
+}
+
+#[derive(Clap, Debug)]
+enum CertsCommand {
+    /// Shows managed certificates and their expiry
+    Status,
+    /// Reissues certificates and restarts deployments using them
+    Renew(ArgsCertsRenew),
+}
+
+#[derive(Debug, Clap)]
+struct ArgsCerts {
+    #[clap(subcommand)]
+    command: CertsCommand,
+}
+
+#[derive(Clap, Debug)]
+struct Opts {
+    /// Project directory. By default it is searched for upwards from
+    /// the current directory.
+    #[clap(long, env = "D_K8S_ROOT", global = true, parse(from_os_str))]
+    root: Option<PathBuf>,
+    /// Cluster to operate on. Defaults to the one selected with
+    /// `k8s clusters use`.
+    #[clap(long, env = "D_K8S_CLUSTER", global = true)]
+    cluster: Option<String>,
+    #[clap(subcommand)]
+    command: Args,
+}
+
 #[derive(Clap, Debug)]
 enum Args {
-    Up,
+    Up(ArgsUp),
     Down,
+    Node(ArgsNode),
     Dash,
     Addons(ArgsAddons),
     K(ArgsK),
     Push(ArgsPush),
     AddUser(ArgsAddUser),
+    Clusters(ArgsClusters),
+    Ca(ArgsCa),
+    Certs(ArgsCerts),
+    /// Checks that nodes, API server and addons are healthy
+    Status,
 }
 
 fn load_configs() -> anyhow::Result<(vm::VmConfig,)> {
-    let vmc = serde_json::from_str(&xshell::read_file(ROOT.join("etc/vm.json"))?)?;
+    let vmc = vm::VmConfig::load(cluster_name())?;
     Ok((vmc,))
 }
 
-async fn up(only_down: bool) -> anyhow::Result<()> {
+async fn up(only_down: bool, workers: usize, resume: bool) -> anyhow::Result<()> {
     let configs = load_configs()?;
     dbg!(&configs);
-    vm::down(&configs.0)?;
+    if !resume {
+        vm::down(&configs.0)?;
+    }
     if only_down {
         return Ok(());
     }
-    let state = vm::create(&configs.0).await?;
-    vm::setup_soft(&state).await?;
+    vm::up(&configs.0, workers, resume).await?;
     Ok(())
 }
 
+async fn node(command: NodeCommand) -> anyhow::Result<()> {
+    let configs = load_configs()?;
+    let mut state = vm::VmState::load().await?;
+    match command {
+        NodeCommand::Add => vm::add_worker(&configs.0, &mut state).await,
+        NodeCommand::Remove(ArgsNodeRemove { name }) => {
+            vm::remove_worker(&configs.0, &mut state, &name).await
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let fut = real_main();
@@ -75,12 +307,45 @@ async fn main() -> anyhow::Result<()> {
 }
 
 async fn real_main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    match args {
-        Args::Up => up(false).await,
-        Args::Down => up(true).await,
+    let opts = Opts::parse();
+    init_root(opts.root)?;
+    clusters::migrate_legacy_state()?;
+    init_cluster(opts.cluster)?;
+    match opts.command {
+        Args::Up(ArgsUp { workers, resume }) => up(false, workers, resume).await,
+        Args::Down => up(true, 0, false).await,
+        Args::Node(ArgsNode { command }) => node(command).await,
         Args::Dash => dashboard::open().await,
-        Args::Addons(ArgsAddons { only_apply, filter }) => {
+        Args::Addons(ArgsAddons {
+            command: Some(AddonsCommand::Remove(ArgsAddonsRemove { name, force })),
+            ..
+        }) => addons::remove(&name, force).await,
+        Args::Addons(ArgsAddons {
+            command: Some(AddonsCommand::List),
+            ..
+        }) => addons::list().await,
+        Args::Addons(ArgsAddons {
+            command: Some(AddonsCommand::Diff(ArgsAddonsDiff { filter, no_deps })),
+            ..
+        }) => {
+            addons::install(
+                true,
+                if filter.is_empty() {
+                    None
+                } else {
+                    Some(&filter)
+                },
+                !no_deps,
+                true,
+            )
+            .await
+        }
+        Args::Addons(ArgsAddons {
+            command: None,
+            only_apply,
+            filter,
+            no_deps,
+        }) => {
             addons::install(
                 only_apply,
                 if filter.is_empty() {
@@ -88,6 +353,8 @@ async fn real_main() -> anyhow::Result<()> {
                 } else {
                     Some(&filter)
                 },
+                !no_deps,
+                false,
             )
             .await
         }
@@ -98,22 +365,35 @@ async fn real_main() -> anyhow::Result<()> {
                 .stdout(std::process::Stdio::inherit())
                 .stderr(std::process::Stdio::inherit())
                 .args(args)
-                .env("KUBECONFIG", ROOT.join("state/kubeconfig"))
+                .env("KUBECONFIG", kubeconfig_path())
                 .status()
                 .await?;
             std::process::exit(status.code().unwrap_or(-1))
         }
         Args::AddUser(ArgsAddUser { name }) => tasks::add_user(&name).await,
+        Args::Clusters(ArgsClusters { command }) => match command {
+            ClustersCommand::List => clusters::list().await,
+            ClustersCommand::Use(ArgsClustersUse { name }) => clusters::set_default(&name).await,
+        },
+        Args::Ca(ArgsCa { command }) => match command {
+            CaCommand::Init(ArgsCaInit { trust, force }) => ca::init(trust, force).await,
+        },
+        Args::Certs(ArgsCerts { command }) => match command {
+            CertsCommand::Status => certs::status().await,
+            CertsCommand::Renew(ArgsCertsRenew { name, all }) => {
+                certs::renew(name.as_deref(), all).await
+            }
+        },
+        Args::Status => status::status().await,
     }
 }
 
 fn configure_kubectl() {
-    std::env::set_var("KUBECONFIG", ROOT.join("state/kubeconfig"));
+    std::env::set_var("KUBECONFIG", kubeconfig_path());
 }
 
 async fn kube() -> anyhow::Result<kube::Client> {
-    let kubeconfig = ROOT.join("state/kubeconfig");
-    let kubeconfig = kube::config::Kubeconfig::read_from(kubeconfig)?;
+    let kubeconfig = kube::config::Kubeconfig::read_from(kubeconfig_path())?;
 
     let config = kube::Config::from_custom_kubeconfig(kubeconfig, &Default::default()).await?;
     Ok(kube::Client::new(config))
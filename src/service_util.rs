@@ -8,8 +8,8 @@ pub async fn resolve_service(ns: &str, name: &str) -> anyhow::Result<String> {
     let svc_api = Api::<v1::Service>::namespaced(k, ns);
     let dash = svc_api.get(name).await?;
     let port = get_node_port(&dash).context("NodePort missing")?;
-    let vm_state: VmState = serde_json::from_str(&tokio::fs::read_to_string(crate::ROOT.join("state/vm.json")).await?)?;
-    Ok(format!("{}:{}", vm_state.ip, port))
+    let vm_state = VmState::load().await?;
+    Ok(format!("{}:{}", vm_state.control_plane()?.ip, port))
 }
 
 
@@ -0,0 +1,166 @@
+use crate::{vm::VmState, watch::Health};
+use k8s_openapi::api::{apps::v1 as appsv1, core::v1};
+use kube::{api::ListParams, Api};
+use std::time::Duration;
+
+const SSH_TIMEOUT_SECS: u64 = 15;
+
+struct Row {
+    check: &'static str,
+    target: String,
+    ok: bool,
+    /// Passed, but needs attention soon
+    warning: bool,
+    details: String,
+}
+
+impl Row {
+    fn from_result(check: &'static str, target: String, res: anyhow::Result<bool>) -> Row {
+        let (ok, details) = match res {
+            Ok(true) => (true, String::new()),
+            Ok(false) => (false, "not ready".to_string()),
+            Err(err) => (false, format!("{:#}", err)),
+        };
+        Row {
+            check,
+            target,
+            ok,
+            warning: false,
+            details,
+        }
+    }
+}
+
+async fn check_ssh(node: &crate::vm::NodeState) -> anyhow::Result<bool> {
+    let mut sess = node.connect().await?;
+    sess.check(&["true"]).await
+}
+
+/// Checks nodes, API server and addons, and prints a summary.
+/// Fails if any of the checks did not pass.
+pub async fn status() -> anyhow::Result<()> {
+    let state = VmState::load().await?;
+    let mut rows = Vec::new();
+
+    for node in &state.nodes {
+        let res = tokio::time::timeout(Duration::from_secs(SSH_TIMEOUT_SECS), check_ssh(node))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+        rows.push(Row::from_result(
+            "ssh",
+            format!("{} ({})", node.name, node.ip),
+            res,
+        ));
+    }
+
+    let api_res = async {
+        let k = crate::kube().await?;
+        let version = k.apiserver_version().await?;
+        Ok::<_, anyhow::Error>((k, version))
+    }
+    .await;
+    match api_res {
+        Ok((k, version)) => {
+            rows.push(Row {
+                check: "apiserver",
+                target: "kubernetes".to_string(),
+                ok: true,
+                warning: false,
+                details: version.git_version,
+            });
+            check_cluster(&k, &mut rows).await;
+        }
+        Err(err) => rows.push(Row::from_result(
+            "apiserver",
+            "kubernetes".to_string(),
+            Err(err),
+        )),
+    }
+
+    println!("{:<10} {:<60} {:<6} DETAILS", "CHECK", "TARGET", "STATUS");
+    for row in &rows {
+        println!(
+            "{:<10} {:<60} {:<6} {}",
+            row.check,
+            row.target,
+            match (row.ok, row.warning) {
+                (false, _) => "FAIL",
+                (true, true) => "WARN",
+                (true, false) => "ok",
+            },
+            row.details
+        );
+    }
+    let failed = rows.iter().filter(|row| !row.ok).count();
+    if failed != 0 {
+        anyhow::bail!("{} of {} checks failed", failed, rows.len());
+    }
+    Ok(())
+}
+
+/// Runs checks which need API server
+async fn check_cluster(k: &kube::Client, rows: &mut Vec<Row>) {
+    let nodes_api = Api::<v1::Node>::all(k.clone());
+    match nodes_api.list(&ListParams::default()).await {
+        Ok(nodes) => {
+            for node in nodes {
+                rows.push(Row::from_result(
+                    "node",
+                    node.metadata.name.clone().unwrap_or_default(),
+                    node.is_healthy(),
+                ));
+            }
+        }
+        Err(err) => rows.push(Row::from_result("node", "all".to_string(), Err(err.into()))),
+    }
+
+    let mut workloads = vec![(
+        "cilium".to_string(),
+        crate::watch::ResourceRef::new::<appsv1::DaemonSet>("kube-system", "cilium"),
+    )];
+    match crate::addons::workloads(k).await {
+        Ok(addon_workloads) => workloads.extend(addon_workloads),
+        Err(err) => rows.push(Row::from_result("addon", "all".to_string(), Err(err))),
+    }
+    for (addon, workload) in workloads {
+        let res = workload.check(k).await;
+        rows.push(Row::from_result(
+            "addon",
+            format!("{}: {}", addon, workload),
+            res,
+        ));
+    }
+
+    match crate::certs::list_managed(k).await {
+        Ok(certs) => {
+            for cert in certs {
+                let target = format!("{}/{}", cert.spec.namespace, cert.spec.secret);
+                let row = match cert.days_left() {
+                    Some(days_left) if days_left < 0 => Row {
+                        check: "cert",
+                        target,
+                        ok: false,
+                        warning: false,
+                        details: "expired".to_string(),
+                    },
+                    Some(days_left) => Row {
+                        check: "cert",
+                        target,
+                        ok: true,
+                        warning: days_left <= crate::certs::EXPIRY_WARNING_DAYS,
+                        details: format!("expires in {} days", days_left),
+                    },
+                    None => Row {
+                        check: "cert",
+                        target,
+                        ok: true,
+                        warning: true,
+                        details: "expiry unknown".to_string(),
+                    },
+                };
+                rows.push(row);
+            }
+        }
+        Err(err) => rows.push(Row::from_result("cert", "all".to_string(), Err(err))),
+    }
+}
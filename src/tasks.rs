@@ -273,7 +273,7 @@ kubectl create secret generic --from-file=key=key.pem \
         };
 
         let our_config: serde_yaml::Value =
-            serde_yaml::from_str(&xshell::read_file(crate::ROOT.join("state/kubeconfig"))?).context("failed to parse local kubeconfig")?;
+            serde_yaml::from_str(&xshell::read_file(crate::kubeconfig_path())?).context("failed to parse local kubeconfig")?;
             let our_config: serde_json::Value =
             serde_yaml::from_value(our_config)?;
         let server = our_config
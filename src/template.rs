@@ -0,0 +1,65 @@
+//! Minimal templating for manifests and configs: `{{ name }}` is replaced
+//! with value of variable `name`. Variables are resolved by the caller,
+//! `variables` tells which ones are used.
+use std::collections::BTreeMap;
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+/// Splits template into literal text and variable names
+fn parse(template: &str) -> anyhow::Result<Vec<(&str, Option<&str>)>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(OPEN) {
+        let literal = &rest[..start];
+        let after_open = &rest[start + OPEN.len()..];
+        let end = match after_open.find(CLOSE) {
+            Some(end) => end,
+            None => {
+                let offset = template.len() - rest.len() + start;
+                let line = template[..offset].matches('\n').count() + 1;
+                anyhow::bail!("unterminated placeholder on line {}", line);
+            }
+        };
+        let name = after_open[..end].trim();
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            anyhow::bail!("invalid variable name {:?}", name);
+        }
+        parts.push((literal, Some(name)));
+        rest = &after_open[end + CLOSE.len()..];
+    }
+    parts.push((rest, None));
+    Ok(parts)
+}
+
+/// Returns names of variables used by the template
+pub fn variables(template: &str) -> anyhow::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for (_, name) in parse(template)? {
+        if let Some(name) = name {
+            if !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Substitutes variables. All used variables must be present in `values`.
+pub fn render(template: &str, values: &BTreeMap<String, String>) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(template.len());
+    for (literal, name) in parse(template)? {
+        out.push_str(literal);
+        if let Some(name) = name {
+            let value = values
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("unknown variable {}", name))?;
+            out.push_str(value);
+        }
+    }
+    Ok(out)
+}
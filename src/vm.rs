@@ -1,27 +1,256 @@
+mod existing;
+mod provision;
+mod yandex;
+
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct VmConfig {
-    #[serde(default = "VmConfig::default_vm_name")]
+    /// Name of the control plane VM, defaults to cluster name
+    #[serde(default)]
     name: String,
+    #[serde(default)]
+    provider: ProviderConfig,
+    /// Availability zone
+    #[serde(default = "VmConfig::default_zone")]
+    zone: String,
+    /// Number of vCPUs
+    #[serde(default = "VmConfig::default_cores")]
+    cores: u32,
+    /// Guaranteed vCPU share, in percents
+    #[serde(default = "VmConfig::default_core_fraction")]
+    core_fraction: u32,
+    /// RAM size in GiB
+    #[serde(default = "VmConfig::default_memory_gb")]
+    memory_gb: u32,
+    /// Boot disk size in GiB
+    #[serde(default = "VmConfig::default_disk_size_gb")]
+    disk_size_gb: u32,
+    /// Folder containing boot disk image family
+    #[serde(default = "VmConfig::default_image_folder_id")]
+    image_folder_id: String,
+    /// Boot disk image family
+    #[serde(default = "VmConfig::default_image_family")]
+    image_family: String,
+    #[serde(default = "VmConfig::default_preemptible")]
+    preemptible: bool,
+    /// Service account attached to the VM, `null` for none
+    #[serde(default = "VmConfig::default_service_account")]
+    service_account: Option<String>,
+    /// Public key which is authorized for SSH access
+    #[serde(
+        default = "VmConfig::default_ssh_public_key",
+        deserialize_with = "crate::config_defs::deserialize_path"
+    )]
+    ssh_public_key: PathBuf,
 }
 
 impl VmConfig {
-    fn default_vm_name() -> String {
-        "k8s".to_string()
+    /// Loads config of the cluster: `etc/clusters/<cluster>.json` if it
+    /// exists, otherwise `etc/vm.json` which is shared by other clusters.
+    pub fn load(cluster: &str) -> anyhow::Result<VmConfig> {
+        let own_path = format!("etc/clusters/{}.json", cluster);
+        let path = if crate::ROOT.join(&own_path).is_file() {
+            own_path
+        } else {
+            "etc/vm.json".to_string()
+        };
+        let mut config: VmConfig = serde_json::from_str(&xshell::read_file(crate::ROOT.join(&path))?)
+            .with_context(|| format!("failed to parse {}", path))?;
+        if config.name.is_empty() {
+            config.name = cluster.to_string();
+        }
+        config
+            .validate()
+            .with_context(|| format!("{} is invalid", path))?;
+        Ok(config)
+    }
+
+    fn default_zone() -> String {
+        "ru-central1-a".to_string()
+    }
+
+    fn default_cores() -> u32 {
+        2
+    }
+
+    fn default_core_fraction() -> u32 {
+        20
+    }
+
+    fn default_memory_gb() -> u32 {
+        8
+    }
+
+    fn default_disk_size_gb() -> u32 {
+        100
+    }
+
+    fn default_image_folder_id() -> String {
+        "standard-images".to_string()
     }
+
+    fn default_image_family() -> String {
+        "ubuntu-2004-lts".to_string()
+    }
+
+    fn default_preemptible() -> bool {
+        true
+    }
+
+    fn default_service_account() -> Option<String> {
+        Some("nobody".to_string())
+    }
+
+    fn default_ssh_public_key() -> PathBuf {
+        crate::config_defs::expand_tilde("~/.ssh/id_rsa.pub".as_ref())
+    }
+
+    /// Checks values which are well-typed but still make no sense
+    pub fn validate(&self) -> anyhow::Result<()> {
+        fn ensure_not_empty(field: &str, value: &str) -> anyhow::Result<()> {
+            if value.is_empty() {
+                anyhow::bail!("invalid `{}`: must not be empty", field);
+            }
+            Ok(())
+        }
+        ensure_not_empty("name", &self.name)?;
+        if self.cores == 0 {
+            anyhow::bail!("invalid `cores`: must be at least 1");
+        }
+        if ![5, 20, 50, 100].contains(&self.core_fraction) {
+            anyhow::bail!(
+                "invalid `core_fraction`: {} is not one of 5, 20, 50, 100",
+                self.core_fraction
+            );
+        }
+        if self.memory_gb == 0 {
+            anyhow::bail!("invalid `memory_gb`: must be at least 1");
+        }
+        if self.disk_size_gb == 0 {
+            anyhow::bail!("invalid `disk_size_gb`: must be at least 1");
+        }
+        if let ProviderConfig::YandexCloud = self.provider {
+            ensure_not_empty("zone", &self.zone)?;
+            ensure_not_empty("image_folder_id", &self.image_folder_id)?;
+            ensure_not_empty("image_family", &self.image_family)?;
+            if let Some(sa) = &self.service_account {
+                ensure_not_empty("service_account", sa)?;
+            }
+            if !self.ssh_public_key.is_file() {
+                anyhow::bail!(
+                    "invalid `ssh_public_key`: {} is not a file",
+                    self.ssh_public_key.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether control plane VM exists
+    pub fn vm_exists(&self) -> anyhow::Result<bool> {
+        Ok(self.provider().describe(&self.name)?.is_some())
+    }
+
+    fn provider(&self) -> Box<dyn VmProvider + '_> {
+        match &self.provider {
+            ProviderConfig::YandexCloud => Box::new(yandex::YandexCloud { config: self }),
+            ProviderConfig::Existing { hosts } => Box::new(existing::ExistingHosts { hosts }),
+        }
+    }
+}
+
+/// Selects where VMs come from
+#[derive(Deserialize, Debug, Default)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum ProviderConfig {
+    /// Instances are created in Yandex Cloud using `yc` CLI
+    #[default]
+    YandexCloud,
+    /// Already running machines reachable over SSH
+    Existing { hosts: Vec<existing::Host> },
+}
+
+/// Addresses of a running VM
+pub struct VmDescription {
+    pub ip: String,
+    pub priv_ip: String,
+    /// User which should be used for SSH connections
+    pub user: String,
+}
+
+/// Backend that manages VM lifecycle.
+///
+/// Implementations only deal with machines themselves; everything that
+/// happens inside a VM is done over SSH regardless of the provider.
+pub trait VmProvider {
+    /// Creates a new VM. Does not wait until it is reachable.
+    fn create(&self, name: &str) -> anyhow::Result<()>;
+
+    /// Returns VM addresses, or `None` if VM does not exist
+    fn describe(&self, name: &str) -> anyhow::Result<Option<VmDescription>>;
+
+    /// Deletes VM. Deleting missing VM is not an error.
+    fn delete(&self, name: &str) -> anyhow::Result<()>;
+
+    /// Returns names of all VMs known to the provider
+    fn list(&self) -> anyhow::Result<Vec<String>>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum NodeRole {
+    ControlPlane,
+    Worker,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NodeState {
+    /// VM name
+    pub name: String,
+    pub role: NodeRole,
+    pub ip: String,
+    priv_ip: String,
+    #[serde(default = "NodeState::default_user")]
+    user: String,
+    /// Name of the Kubernetes Node object, known after node is set up
+    #[serde(default)]
+    pub hostname: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Cluster nodes, stored in `state/<cluster>/vm.json`
+#[derive(Serialize, Deserialize, Default)]
 pub struct VmState {
+    pub nodes: Vec<NodeState>,
+}
+
+/// Single-node state written by older versions to `state/vm.json`
+#[derive(Deserialize)]
+pub struct LegacyVmState {
     name: String,
-    pub ip: String,
+    ip: String,
     priv_ip: String,
 }
 
+impl From<LegacyVmState> for VmState {
+    fn from(legacy: LegacyVmState) -> VmState {
+        VmState {
+            nodes: vec![NodeState {
+                name: legacy.name,
+                role: NodeRole::ControlPlane,
+                ip: legacy.ip,
+                priv_ip: legacy.priv_ip,
+                user: NodeState::default_user(),
+                hostname: None,
+            }],
+        }
+    }
+}
+
 pub struct Sess(openssh::Session);
 
 impl Sess {
@@ -74,6 +303,31 @@ impl Sess {
         Ok(())
     }
 
+    /// Runs command silently and returns whether it succeeded
+    pub async fn check(&mut self, args: &[&str]) -> anyhow::Result<bool> {
+        let mut cmd = self.0.command(args[0]);
+        cmd.raw_args(args.iter().skip(1));
+        cmd.stderr(std::process::Stdio::null());
+        cmd.stdout(std::process::Stdio::null());
+        let status = cmd.status().await.context("failed to spawn")?;
+        Ok(status.success())
+    }
+
+    /// Runs command and returns its stdout
+    pub async fn read(&mut self, args: &[&str]) -> anyhow::Result<String> {
+        let mut cmd = self.0.command(args[0]);
+        cmd.raw_args(args.iter().skip(1));
+        let output = cmd.output().await.context("failed to spawn")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Child process failed: code {:?}: {}",
+                output.status.code(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        String::from_utf8(output.stdout).context("command output is not utf8")
+    }
+
     pub async fn send(&mut self, path: &str, data: &[u8]) -> anyhow::Result<()> {
         let mut sftp = self.0.sftp();
         let mut remote_file = sftp
@@ -104,61 +358,94 @@ impl Sess {
     }
 }
 
-impl VmState {
+impl NodeState {
+    fn default_user() -> String {
+        "yc-user".to_string()
+    }
+
     pub async fn connect(&self) -> anyhow::Result<Sess> {
-        openssh::Session::connect(format!("yc-user@{}", self.ip), openssh::KnownHosts::Accept)
+        openssh::Session::connect(
+            format!("{}@{}", self.user, self.ip),
+            openssh::KnownHosts::Accept,
+        )
+        .await
+        .map_err(Into::into)
+        .map(Sess)
+    }
+}
+
+impl VmState {
+    /// Loads state of the current cluster
+    pub async fn load() -> anyhow::Result<VmState> {
+        Self::load_cluster(crate::cluster_name()).await
+    }
+
+    pub async fn load_cluster(cluster: &str) -> anyhow::Result<VmState> {
+        let path = crate::clusters::state_dir(cluster).join("vm.json");
+        let data = tokio::fs::read(path)
             .await
-            .map_err(Into::into)
-            .map(Sess)
+            .context("failed to read VM state, is cluster created?")?;
+        serde_json::from_slice(&data).context("failed to parse VM state")
+    }
+
+    pub async fn save(&self) -> anyhow::Result<()> {
+        let state_str = serde_json::to_string(self)?;
+        tokio::fs::create_dir_all(crate::state_dir()).await?;
+        tokio::fs::write(crate::state_dir().join("vm.json"), state_str).await?;
+        Ok(())
+    }
+
+    pub fn control_plane(&self) -> anyhow::Result<&NodeState> {
+        self.nodes
+            .iter()
+            .find(|n| n.role == NodeRole::ControlPlane)
+            .context("cluster has no control plane node")
+    }
+
+    fn next_worker_name(&self, config: &VmConfig) -> String {
+        let prefix = format!("{}-worker-", config.name);
+        let last = self
+            .nodes
+            .iter()
+            .filter_map(|n| n.name.strip_prefix(&prefix))
+            .filter_map(|idx| idx.parse::<u32>().ok())
+            .max()
+            .unwrap_or(0);
+        format!("{}{}", prefix, last + 1)
     }
 }
 
 const MAX_ATTEMPTS: usize = 6;
-pub fn down(config: &VmConfig) -> anyhow::Result<()> {
-    let vm_name = &config.name;
-    xshell::cmd!("yc compute instance delete --name {vm_name}")
-        .run()
-        .ok();
 
+/// Deletes all VMs of the cluster
+pub fn down(config: &VmConfig) -> anyhow::Result<()> {
+    let provider = config.provider();
+    let worker_prefix = format!("{}-worker-", config.name);
+    for vm_name in provider.list()? {
+        if vm_name == config.name || vm_name.starts_with(&worker_prefix) {
+            provider.delete(&vm_name)?;
+        }
+    }
     Ok(())
 }
 
-pub async fn create(config: &VmConfig) -> anyhow::Result<VmState> {
-    let vm_name = &config.name;
-
-    let cmd = xshell::cmd!(
-        "yc compute instance create 
-    --name {vm_name}
-    --zone ru-central1-a
-    --public-ip
-    --preemptible
-    --service-account-name nobody
-    --cores 2
-    --core-fraction 20
-    --memory 8g
-    --create-boot-disk image-folder-id=standard-images,image-family=ubuntu-2004-lts,size=100
-    --ssh-key /home/mb/.ssh/id_rsa.pub"
-    );
-    cmd.run()?;
-
-    let vm_desc =
-        xshell::cmd!("yc --format json-rest compute instance get --name {vm_name}").read()?;
-    let vm_desc: serde_json::Value = serde_json::from_str(&vm_desc)?;
-
-    let state = VmState {
-        name: config.name.clone(),
-        ip: vm_desc
-            .pointer("/networkInterfaces/0/primaryV4Address/oneToOneNat/address")
-            .context("bad pointer or response")?
-            .as_str()
-            .context("wtf not string")?
-            .to_string(),
-        priv_ip: vm_desc
-            .pointer("/networkInterfaces/0/primaryV4Address/address")
-            .context("bad pointer or response")?
-            .as_str()
-            .context("wtf not string")?
-            .to_string(),
+/// Creates a VM and waits until it accepts SSH connections
+pub async fn create(config: &VmConfig, name: &str, role: NodeRole) -> anyhow::Result<NodeState> {
+    let provider = config.provider();
+    provider.create(name)?;
+    provision::reset_progress(name).await?;
+
+    let vm_desc = provider
+        .describe(name)?
+        .context("VM is missing right after creation")?;
+
+    let state = NodeState {
+        name: name.to_string(),
+        role,
+        ip: vm_desc.ip,
+        priv_ip: vm_desc.priv_ip,
+        user: vm_desc.user,
+        hostname: None,
     };
 
     println!("Waiting for VM to become ready");
@@ -183,221 +470,97 @@ pub async fn create(config: &VmConfig) -> anyhow::Result<VmState> {
         }
     }
 
-    println!("Saving VM state");
-
-    let state_str = serde_json::to_string(&state)?;
-    tokio::fs::write(crate::ROOT.join("state/vm.json"), state_str).await?;
-
     Ok(state)
 }
 
-pub async fn setup_soft(state: &VmState) -> anyhow::Result<()> {
-    println!("Establishing connection to vm");
-    let mut sess = state.connect().await?;
-    println!("Updating apt index");
-    sess.run(&["sudo", "apt-get", "update"]).await?;
-    println!("Installing packages");
-    sess.run(&[
-        "sudo",
-        "apt-get",
-        "install",
-        "-y",
-        "apt-transport-https",
-        "ca-certificates",
-        "curl",
-        "software-properties-common",
-        "gnupg2",
-    ])
-    .await?;
-    println!("Trusting local CA");
-    let ca_settings: crate::config_defs::CaSettings =
-        serde_json::from_slice(&tokio::fs::read(crate::ROOT.join("etc/ca.json")).await?)?;
-    let ca_certificate = tokio::fs::read(&ca_settings.certificate).await?;
-    sess.send("/tmp/ca-cert", &ca_certificate).await?;
-    sess.run(&[
-        "sudo",
-        "cp",
-        "/tmp/ca-cert",
-        "/usr/local/share/ca-certificates/local-ca.crt",
-    ])
-    .await?;
-    sess.run(&["sudo", "update-ca-certificates"]).await?;
-    /*println!("Adding Docker GPG key");
-    sess.run(&[
-        "curl",
-        "-fsSL",
-        "https://download.docker.com/linux/ubuntu/gpg",
-        "|",
-        "sudo",
-        "apt-key",
-        "add",
-        "-",
-    ])
-    .await?;*/
-    println!("Adding Kubernetes GPG key");
-    sess.run(&[
-        "curl",
-        "-fsSL",
-        "https://packages.cloud.google.com/apt/doc/apt-key.gpg",
-        "|",
-        "sudo",
-        "apt-key",
-        "add",
-        "-",
-    ])
-    .await?;
-    println!("Adding Kubernetes APT repository");
-    sess.run(&[
-        "sudo",
-        "add-apt-repository",
-        "\"deb https://apt.kubernetes.io kubernetes-xenial main\"",
-    ])
-    .await?;
-    /*
-    println!("Adding Docker APT repository");
-    sess.run(&[
-        "sudo",
-        "add-apt-repository",
-        "\"deb [arch=amd64] https://download.docker.com/linux/ubuntu focal stable\"",
-    ])
-    .await?;*/
-
-    println!("Updating APT db again");
-    sess.run(&["sudo", "apt-get", "update"]).await?;
-    println!("Preparing node for containerd");
-    {
-        let modules_load_config = r#"
-overlay
-br_netfilter        
-        "#;
-        let modules_load_path = "/etc/modules-load.d/containerd.conf";
-        let tmp_path = "/tmp/containerd-modload";
-        sess.send(tmp_path, modules_load_config.as_bytes()).await?;
-        sess.run(&["sudo", "cp", tmp_path, modules_load_path])
-            .await?;
-        sess.run(&["sudo", "modprobe", "overlay"]).await?;
-        sess.run(&["sudo", "modprobe", "br_netfilter"]).await?;
+/// Creates whole cluster: control plane node and `workers` worker nodes.
+/// If `resume` is set, existing VMs are reused and their provisioning
+/// continues from the first step which was not completed.
+pub async fn up(config: &VmConfig, workers: usize, resume: bool) -> anyhow::Result<()> {
+    let mut state = if resume {
+        VmState::load().await?
+    } else {
+        VmState::default()
+    };
+    if state.control_plane().is_err() {
+        let control_plane = create(config, &config.name, NodeRole::ControlPlane).await?;
+        state.nodes.insert(0, control_plane);
+        println!("Saving VM state");
+        state.save().await?;
     }
+    // on resume `workers` may be omitted, nodes from the state still count
+    let schedulable = workers == 0 && !state.nodes.iter().any(|n| n.role == NodeRole::Worker);
+    let control_plane = state
+        .nodes
+        .iter_mut()
+        .find(|n| n.role == NodeRole::ControlPlane)
+        .expect("control plane was created above");
+    provision::setup_control_plane(control_plane, schedulable).await?;
+    state.save().await?;
+    let control_plane = state.control_plane()?.clone();
+    for worker in state
+        .nodes
+        .iter_mut()
+        .filter(|n| n.role == NodeRole::Worker)
     {
-        let sysctls_config = r#"
-net.bridge.bridge-nf-call-iptables  = 1
-net.ipv4.ip_forward                 = 1
-net.bridge.bridge-nf-call-ip6tables = 1        
-        "#;
-        let sysctls_path = "/etc/sysctl.d/99-kubernetes-cri.conf";
-        let tmp_path = "/tmp/containerd-modload";
-        sess.send(tmp_path, sysctls_config.as_bytes()).await?;
-        sess.run(&["sudo", "cp", tmp_path, sysctls_path]).await?;
-        sess.run(&["sudo", "sysctl", "--system"]).await?;
-    }
-    println!("Installing containerd");
-    sess.run(&["sudo", "apt-get", "install", "-y", "containerd"])
-        .await?;
-    println!("Configuring containerd");
-    sess.run(&["sudo", "mkdir", "/etc/containerd"]).await?;
-    sess.run(&[
-        "sudo",
-        "containerd",
-        "config",
-        "default",
-        "|",
-        "sudo",
-        "tee",
-        "/etc/containerd/config.toml",
-    ])
-    .await?;
-    sess.run(&["sudo", "systemctl", "restart", "containerd"])
+        provision::setup_worker(&control_plane, worker).await?;
+    }
+    state.save().await?;
+    let existing_workers = state.nodes.len() - 1;
+    for _ in existing_workers..workers {
+        add_worker(config, &mut state).await?;
+    }
+    println!("Waiting for network to become ready");
+    let k = crate::kube().await?;
+    crate::watch::watch::<k8s_openapi::api::apps::v1::DaemonSet>(&k, "kube-system", "cilium", 300)
         .await?;
+    Ok(())
+}
 
-    println!("Installing Kubernetes");
-    sess.run(&[
-        "sudo", "apt-get", "install", "-y", "kubelet", "kubeadm", "kubectl",
-    ])
-    .await?;
-    println!("Disabling swap");
-    sess.run(&["sudo", "swapoff", "-a"]).await?;
-    println!("Loading k8s images");
-    sess.run(&["sudo", "kubeadm", "config", "images", "pull"])
-        .await?;
-    println!("Pushing kubeadm config");
-    let config_data = tokio::fs::read_to_string(crate::ROOT.join("etc/kubeadm.yaml"))
-        .await?
-        .replace("__PUB_IP__", &state.ip);
-    sess.send("/tmp/kubeadm.yaml", config_data.as_bytes())
-        .await?;
+/// Creates new worker VM and joins it to the cluster
+pub async fn add_worker(config: &VmConfig, state: &mut VmState) -> anyhow::Result<()> {
+    let name = state.next_worker_name(config);
+    println!("Creating worker {}", name);
+    let worker = create(config, &name, NodeRole::Worker).await?;
+    state.nodes.push(worker);
+    state.save().await?;
+    let control_plane = state.control_plane()?.clone();
+    let worker = state.nodes.last_mut().expect("worker was just added");
+    provision::setup_worker(&control_plane, worker).await?;
+    state.save().await?;
+    Ok(())
+}
 
-    println!("Running kubeadm init");
-    sess.run(&["sudo", "kubeadm", "init", "--config", "/tmp/kubeadm.yaml"])
-        .await?;
-    println!("Configuring master kubectl");
-    sess.run(&["mkdir", "-p", "/home/yc-user/.kube"]).await?;
-    sess.run(&[
-        "sudo",
-        "cp",
-        "-i",
-        "/etc/kubernetes/admin.conf",
-        "/home/yc-user/.kube/config",
-    ])
-    .await?;
-    sess.run(&[
-        "sudo",
-        "chown",
-        "$(id -u):$(id -g)",
-        "/home/yc-user/.kube/config",
-    ])
-    .await?;
-    println!("Allowing master to execute pods");
-    sess.run(&[
-        "kubectl",
-        "taint",
-        "nodes",
-        "--all",
-        "node-role.kubernetes.io/master-",
-    ])
-    .await?;
-    sess.run(&[
-        "kubectl",
-        "annotate",
-        "nodes",
-        "--all",
-        &format!("d-k8s.io/public-ip={}", state.ip),
-    ])
-    .await?;
-    println!("Installing cilium");
-    sess.run(&["kubectl", "create", "-f", "https://raw.githubusercontent.com/cilium/cilium/1.9.0/install/kubernetes/quick-install.yaml"]).await?;
-    println!("Rescaling cilium");
-    sess.run(&[
-        "kubectl",
-        "scale",
-        "-n",
-        "kube-system",
-        "--replicas=1",
-        "deployments/cilium-operator",
-    ])
-    .await?;
-    println!("Downloading kubeconfig");
-    let kubeconfig = sess.pull("/home/yc-user/.kube/config").await?;
-    let kubeconfig =
-        String::from_utf8(kubeconfig)?.replace(&state.priv_ip.to_string(), &state.ip.to_string());
-    let kubeconfig_path = crate::ROOT.join("state/kubeconfig");
-    tokio::fs::write(&kubeconfig_path, kubeconfig).await?;
-    println!("Adding to ~/.kube/config");
-    let global_kc_path = dirs::home_dir()
-        .context("home dir not found")?
-        .join(".kube/config");
-    {
-        let _e = xshell::pushenv(
-            "KUBECONFIG",
-            format!("{}:{}", kubeconfig_path.display(), global_kc_path.display()),
-        );
-        let merged = xshell::cmd!("kubectl config view --flatten").read()?;
-        xshell::write_file(global_kc_path, merged)?;
+/// Drains worker, removes it from the cluster and deletes its VM
+pub async fn remove_worker(
+    config: &VmConfig,
+    state: &mut VmState,
+    name: &str,
+) -> anyhow::Result<()> {
+    let idx = state
+        .nodes
+        .iter()
+        .position(|n| n.name == name)
+        .with_context(|| format!("node {} not found", name))?;
+    if state.nodes[idx].role == NodeRole::ControlPlane {
+        anyhow::bail!("control plane node can not be removed, use `k8s down` instead");
+    }
+    if let Some(hostname) = state.nodes[idx].hostname.clone() {
+        println!("Draining node {}", hostname);
+        crate::configure_kubectl();
+        xshell::cmd!("kubectl drain {hostname} --ignore-daemonsets --delete-emptydir-data --force")
+            .run()?;
+        xshell::cmd!("kubectl delete node {hostname}").run()?;
     }
+    println!("Deleting VM {}", name);
+    config.provider().delete(name)?;
+    provision::reset_progress(name).await?;
+    state.nodes.remove(idx);
+    state.save().await?;
     Ok(())
 }
 
 pub async fn vm_ip() -> anyhow::Result<String> {
-    let vm_state: crate::vm::VmState =
-        serde_json::from_slice(&tokio::fs::read(crate::ROOT.join("state/vm.json")).await?)?;
-    Ok(vm_state.ip)
+    let vm_state = VmState::load().await?;
+    Ok(vm_state.control_plane()?.ip.clone())
 }
@@ -0,0 +1,61 @@
+use super::{VmDescription, VmProvider};
+use serde::Deserialize;
+
+/// Machine that already exists, e.g. spare box or CI runner
+#[derive(Deserialize, Debug)]
+pub struct Host {
+    /// VM name this host is used for
+    name: String,
+    /// Address used for SSH and for reaching services from outside
+    address: String,
+    /// Address used inside the cluster. Defaults to `address`.
+    #[serde(default)]
+    private_address: Option<String>,
+    /// SSH user, must be able to use sudo without password
+    user: String,
+}
+
+/// Uses hosts listed in the config instead of creating VMs.
+/// Hosts are never created or destroyed: deletion only resets kubeadm on them.
+pub struct ExistingHosts<'a> {
+    pub hosts: &'a [Host],
+}
+
+impl ExistingHosts<'_> {
+    fn find(&self, name: &str) -> Option<&Host> {
+        self.hosts.iter().find(|h| h.name == name)
+    }
+}
+
+impl VmProvider for ExistingHosts<'_> {
+    fn create(&self, name: &str) -> anyhow::Result<()> {
+        if self.find(name).is_none() {
+            anyhow::bail!("Host '{}' is not listed in etc/vm.json", name);
+        }
+        Ok(())
+    }
+
+    fn describe(&self, name: &str) -> anyhow::Result<Option<VmDescription>> {
+        Ok(self.find(name).map(|host| VmDescription {
+            ip: host.address.clone(),
+            priv_ip: host
+                .private_address
+                .clone()
+                .unwrap_or_else(|| host.address.clone()),
+            user: host.user.clone(),
+        }))
+    }
+
+    fn delete(&self, name: &str) -> anyhow::Result<()> {
+        if let Some(host) = self.find(name) {
+            println!("Resetting kubeadm on host '{}'", name);
+            let dest = format!("{}@{}", host.user, host.address);
+            xshell::cmd!("ssh {dest} sudo kubeadm reset -f").run().ok();
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.hosts.iter().map(|h| h.name.clone()).collect())
+    }
+}
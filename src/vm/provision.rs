@@ -0,0 +1,497 @@
+//! Node provisioning, split into named resumable steps.
+//!
+//! Every step has a probe which tells whether step was already done
+//! (e.g. by a previous failed run), and completed steps are recorded
+//! in `state/<cluster>/provision/<vm-name>.json`.
+use super::{NodeState, Sess};
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    InstallBasePackages,
+    TrustLocalCa,
+    AddKubernetesRepository,
+    ConfigureKernel,
+    InstallContainerd,
+    ConfigureContainerd,
+    InstallKubernetes,
+    DisableSwap,
+    // control plane only
+    PullImages,
+    KubeadmInit,
+    ConfigureKubectl,
+    AllowScheduling,
+    AnnotateNode,
+    InstallCilium,
+    RescaleCilium,
+    DownloadKubeconfig,
+    // workers only
+    KubeadmJoin,
+    AnnotateWorker,
+}
+
+const COMMON_STEPS: &[Step] = &[
+    Step::InstallBasePackages,
+    Step::TrustLocalCa,
+    Step::AddKubernetesRepository,
+    Step::ConfigureKernel,
+    Step::InstallContainerd,
+    Step::ConfigureContainerd,
+    Step::InstallKubernetes,
+    Step::DisableSwap,
+];
+
+struct StepCtx<'a> {
+    node: &'a NodeState,
+    hostname: &'a str,
+    /// Set when provisioning worker
+    control_plane: Option<&'a NodeState>,
+}
+
+impl StepCtx<'_> {
+    fn control_plane(&self) -> anyhow::Result<&NodeState> {
+        self.control_plane
+            .context("step is only valid for worker nodes")
+    }
+}
+
+impl Step {
+    fn name(self) -> &'static str {
+        match self {
+            Step::InstallBasePackages => "install-base-packages",
+            Step::TrustLocalCa => "trust-local-ca",
+            Step::AddKubernetesRepository => "add-kubernetes-repository",
+            Step::ConfigureKernel => "configure-kernel",
+            Step::InstallContainerd => "install-containerd",
+            Step::ConfigureContainerd => "configure-containerd",
+            Step::InstallKubernetes => "install-kubernetes",
+            Step::DisableSwap => "disable-swap",
+            Step::PullImages => "pull-images",
+            Step::KubeadmInit => "kubeadm-init",
+            Step::ConfigureKubectl => "configure-kubectl",
+            Step::AllowScheduling => "allow-scheduling",
+            Step::AnnotateNode => "annotate-node",
+            Step::InstallCilium => "install-cilium",
+            Step::RescaleCilium => "rescale-cilium",
+            Step::DownloadKubeconfig => "download-kubeconfig",
+            Step::KubeadmJoin => "kubeadm-join",
+            Step::AnnotateWorker => "annotate-worker",
+        }
+    }
+
+    /// Returns true if effects of this step are already present on the node.
+    /// Steps which are cheap and idempotent do not have probes.
+    async fn probe(self, sess: &mut Sess, ctx: &StepCtx<'_>) -> anyhow::Result<bool> {
+        match self {
+            Step::InstallBasePackages => {
+                sess.check(&[
+                    "dpkg",
+                    "-s",
+                    "apt-transport-https",
+                    "ca-certificates",
+                    "curl",
+                    "software-properties-common",
+                    "gnupg2",
+                ])
+                .await
+            }
+            Step::AddKubernetesRepository => {
+                sess.check(&[
+                    "grep",
+                    "-rq",
+                    "apt.kubernetes.io",
+                    "/etc/apt/sources.list",
+                    "/etc/apt/sources.list.d/",
+                ])
+                .await
+            }
+            Step::ConfigureKernel => {
+                sess.check(&[
+                    "test",
+                    "-f",
+                    "/etc/modules-load.d/containerd.conf",
+                    "&&",
+                    "test",
+                    "-f",
+                    "/etc/sysctl.d/99-kubernetes-cri.conf",
+                ])
+                .await
+            }
+            Step::InstallContainerd => sess.check(&["dpkg", "-s", "containerd"]).await,
+            Step::ConfigureContainerd => {
+                sess.check(&["test", "-f", "/etc/containerd/config.toml"])
+                    .await
+            }
+            Step::InstallKubernetes => {
+                sess.check(&["dpkg", "-s", "kubelet", "kubeadm", "kubectl"])
+                    .await
+            }
+            Step::DisableSwap => sess.check(&["test", "-z", "\"$(swapon --show)\""]).await,
+            Step::PullImages | Step::KubeadmInit => {
+                sess.check(&["test", "-f", "/etc/kubernetes/admin.conf"])
+                    .await
+            }
+            Step::ConfigureKubectl => sess.check(&["test", "-f", "$HOME/.kube/config"]).await,
+            Step::AllowScheduling => {
+                let taints = format!(
+                    "\"$(kubectl get node {} -o jsonpath='{{.spec.taints}}' | grep node-role.kubernetes.io/master)\"",
+                    ctx.hostname
+                );
+                sess.check(&["test", "-z", &taints]).await
+            }
+            Step::InstallCilium => {
+                sess.check(&["kubectl", "-n", "kube-system", "get", "daemonset", "cilium"])
+                    .await
+            }
+            Step::KubeadmJoin => {
+                sess.check(&["test", "-f", "/etc/kubernetes/kubelet.conf"])
+                    .await
+            }
+            Step::TrustLocalCa
+            | Step::AnnotateNode
+            | Step::RescaleCilium
+            | Step::DownloadKubeconfig
+            | Step::AnnotateWorker => Ok(false),
+        }
+    }
+
+    async fn run(self, sess: &mut Sess, ctx: &StepCtx<'_>) -> anyhow::Result<()> {
+        match self {
+            Step::InstallBasePackages => {
+                println!("Updating apt index");
+                sess.run(&["sudo", "apt-get", "update"]).await?;
+                println!("Installing packages");
+                sess.run(&[
+                    "sudo",
+                    "apt-get",
+                    "install",
+                    "-y",
+                    "apt-transport-https",
+                    "ca-certificates",
+                    "curl",
+                    "software-properties-common",
+                    "gnupg2",
+                ])
+                .await?;
+            }
+            Step::TrustLocalCa => {
+                println!("Trusting local CA");
+                let ca_settings = crate::config_defs::CaSettings::load()?;
+                let ca_certificate = tokio::fs::read(&ca_settings.certificate).await?;
+                sess.send("/tmp/ca-cert", &ca_certificate).await?;
+                sess.run(&[
+                    "sudo",
+                    "cp",
+                    "/tmp/ca-cert",
+                    "/usr/local/share/ca-certificates/local-ca.crt",
+                ])
+                .await?;
+                sess.run(&["sudo", "update-ca-certificates"]).await?;
+            }
+            Step::AddKubernetesRepository => {
+                println!("Adding Kubernetes GPG key");
+                sess.run(&[
+                    "curl",
+                    "-fsSL",
+                    "https://packages.cloud.google.com/apt/doc/apt-key.gpg",
+                    "|",
+                    "sudo",
+                    "apt-key",
+                    "add",
+                    "-",
+                ])
+                .await?;
+                println!("Adding Kubernetes APT repository");
+                sess.run(&[
+                    "sudo",
+                    "add-apt-repository",
+                    "\"deb https://apt.kubernetes.io kubernetes-xenial main\"",
+                ])
+                .await?;
+                println!("Updating APT db again");
+                sess.run(&["sudo", "apt-get", "update"]).await?;
+            }
+            Step::ConfigureKernel => {
+                println!("Preparing node for containerd");
+                let modules_load_config = r#"
+overlay
+br_netfilter
+        "#;
+                let modules_load_path = "/etc/modules-load.d/containerd.conf";
+                let tmp_path = "/tmp/containerd-modload";
+                sess.send(tmp_path, modules_load_config.as_bytes()).await?;
+                sess.run(&["sudo", "cp", tmp_path, modules_load_path])
+                    .await?;
+                sess.run(&["sudo", "modprobe", "overlay"]).await?;
+                sess.run(&["sudo", "modprobe", "br_netfilter"]).await?;
+
+                let sysctls_config = r#"
+net.bridge.bridge-nf-call-iptables  = 1
+net.ipv4.ip_forward                 = 1
+net.bridge.bridge-nf-call-ip6tables = 1
+        "#;
+                let sysctls_path = "/etc/sysctl.d/99-kubernetes-cri.conf";
+                sess.send(tmp_path, sysctls_config.as_bytes()).await?;
+                sess.run(&["sudo", "cp", tmp_path, sysctls_path]).await?;
+                sess.run(&["sudo", "sysctl", "--system"]).await?;
+            }
+            Step::InstallContainerd => {
+                println!("Installing containerd");
+                sess.run(&["sudo", "apt-get", "install", "-y", "containerd"])
+                    .await?;
+            }
+            Step::ConfigureContainerd => {
+                println!("Configuring containerd");
+                sess.run(&["sudo", "mkdir", "-p", "/etc/containerd"])
+                    .await?;
+                sess.run(&[
+                    "sudo",
+                    "containerd",
+                    "config",
+                    "default",
+                    "|",
+                    "sudo",
+                    "tee",
+                    "/etc/containerd/config.toml",
+                ])
+                .await?;
+                sess.run(&["sudo", "systemctl", "restart", "containerd"])
+                    .await?;
+            }
+            Step::InstallKubernetes => {
+                println!("Installing Kubernetes");
+                sess.run(&[
+                    "sudo", "apt-get", "install", "-y", "kubelet", "kubeadm", "kubectl",
+                ])
+                .await?;
+            }
+            Step::DisableSwap => {
+                println!("Disabling swap");
+                sess.run(&["sudo", "swapoff", "-a"]).await?;
+            }
+            Step::PullImages => {
+                println!("Loading k8s images");
+                sess.run(&["sudo", "kubeadm", "config", "images", "pull"])
+                    .await?;
+            }
+            Step::KubeadmInit => {
+                println!("Pushing kubeadm config");
+                let template = tokio::fs::read_to_string(crate::ROOT.join("etc/kubeadm.yaml")).await?;
+                let mut variables = BTreeMap::new();
+                variables.insert("public_ip".to_string(), ctx.node.ip.clone());
+                variables.insert("cluster_name".to_string(), crate::cluster_name().to_string());
+                let config_data = crate::template::render(&template, &variables)
+                    .context("failed to render etc/kubeadm.yaml")?;
+                sess.send("/tmp/kubeadm.yaml", config_data.as_bytes())
+                    .await?;
+                // cleans up leftovers of previous failed attempt, if any
+                sess.run(&["sudo", "kubeadm", "reset", "-f"]).await?;
+                println!("Running kubeadm init");
+                sess.run(&["sudo", "kubeadm", "init", "--config", "/tmp/kubeadm.yaml"])
+                    .await?;
+            }
+            Step::ConfigureKubectl => {
+                println!("Configuring master kubectl");
+                sess.run(&["mkdir", "-p", "$HOME/.kube"]).await?;
+                sess.run(&[
+                    "sudo",
+                    "cp",
+                    "/etc/kubernetes/admin.conf",
+                    "$HOME/.kube/config",
+                ])
+                .await?;
+                sess.run(&["sudo", "chown", "$(id -u):$(id -g)", "$HOME/.kube/config"])
+                    .await?;
+            }
+            Step::AllowScheduling => {
+                println!("Allowing master to execute pods");
+                sess.run(&[
+                    "kubectl",
+                    "taint",
+                    "nodes",
+                    ctx.hostname,
+                    "node-role.kubernetes.io/master-",
+                ])
+                .await?;
+            }
+            Step::AnnotateNode => {
+                sess.run(&[
+                    "kubectl",
+                    "annotate",
+                    "--overwrite",
+                    "nodes",
+                    ctx.hostname,
+                    &format!("d-k8s.io/public-ip={}", ctx.node.ip),
+                ])
+                .await?;
+            }
+            Step::InstallCilium => {
+                println!("Installing cilium");
+                sess.run(&["kubectl", "create", "-f", "https://raw.githubusercontent.com/cilium/cilium/1.9.0/install/kubernetes/quick-install.yaml"]).await?;
+            }
+            Step::RescaleCilium => {
+                // cilium-operator replicas use anti-affinity, so only one fits into single-node cluster
+                println!("Rescaling cilium");
+                sess.run(&[
+                    "kubectl",
+                    "scale",
+                    "-n",
+                    "kube-system",
+                    "--replicas=1",
+                    "deployments/cilium-operator",
+                ])
+                .await?;
+            }
+            Step::DownloadKubeconfig => {
+                println!("Downloading kubeconfig");
+                let home = sess.read(&["echo", "$HOME"]).await?;
+                let kubeconfig = sess.pull(&format!("{}/.kube/config", home.trim())).await?;
+                // admin user has the same name in all clusters, rename it
+                // so that clusters do not clash in ~/.kube/config
+                let kubeconfig = String::from_utf8(kubeconfig)?
+                    .replace(&ctx.node.priv_ip, &ctx.node.ip)
+                    .replace(
+                        "kubernetes-admin",
+                        &format!("{}-admin", crate::cluster_name()),
+                    );
+                let kubeconfig_path = crate::kubeconfig_path();
+                tokio::fs::write(&kubeconfig_path, kubeconfig).await?;
+                println!("Adding to ~/.kube/config");
+                let global_kc_path = dirs::home_dir()
+                    .context("home dir not found")?
+                    .join(".kube/config");
+                let _e = xshell::pushenv(
+                    "KUBECONFIG",
+                    format!("{}:{}", kubeconfig_path.display(), global_kc_path.display()),
+                );
+                let merged = xshell::cmd!("kubectl config view --flatten").read()?;
+                xshell::write_file(global_kc_path, merged)?;
+            }
+            Step::KubeadmJoin => {
+                println!("Obtaining join command");
+                let mut cp_sess = ctx.control_plane()?.connect().await?;
+                let join_command = cp_sess
+                    .read(&["sudo", "kubeadm", "token", "create", "--print-join-command"])
+                    .await?;
+                let mut join_args = vec!["sudo"];
+                join_args.extend(join_command.split_whitespace());
+                sess.run(&["sudo", "kubeadm", "reset", "-f"]).await?;
+                println!("Running kubeadm join");
+                sess.run(&join_args).await?;
+            }
+            Step::AnnotateWorker => {
+                println!("Annotating node");
+                let mut cp_sess = ctx.control_plane()?.connect().await?;
+                cp_sess
+                    .run(&[
+                        "kubectl",
+                        "annotate",
+                        "--overwrite",
+                        "nodes",
+                        ctx.hostname,
+                        &format!("d-k8s.io/public-ip={}", ctx.node.ip),
+                    ])
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Names of completed steps for a single node
+#[derive(Serialize, Deserialize, Default)]
+struct Progress {
+    completed: Vec<String>,
+}
+
+fn progress_dir() -> PathBuf {
+    crate::state_dir().join("provision")
+}
+
+fn progress_path(vm_name: &str) -> PathBuf {
+    progress_dir().join(format!("{}.json", vm_name))
+}
+
+impl Progress {
+    async fn load(vm_name: &str) -> anyhow::Result<Progress> {
+        match tokio::fs::read(progress_path(vm_name)).await {
+            Ok(data) => serde_json::from_slice(&data).context("failed to parse progress"),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Progress::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn save(&self, vm_name: &str) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(progress_dir()).await?;
+        tokio::fs::write(progress_path(vm_name), serde_json::to_vec(self)?).await?;
+        Ok(())
+    }
+}
+
+/// Forgets provisioning progress of a VM, e.g. because VM was recreated
+pub async fn reset_progress(vm_name: &str) -> anyhow::Result<()> {
+    match tokio::fs::remove_file(progress_path(vm_name)).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+async fn run_steps(
+    node: &mut NodeState,
+    control_plane: Option<&NodeState>,
+    steps: &[Step],
+) -> anyhow::Result<()> {
+    println!("Establishing connection to vm");
+    let mut sess = node.connect().await?;
+    let hostname = sess.read(&["hostname"]).await?.trim().to_string();
+    let mut progress = Progress::load(&node.name).await?;
+    {
+        let ctx = StepCtx {
+            node,
+            hostname: &hostname,
+            control_plane,
+        };
+        for &step in steps {
+            if progress.completed.iter().any(|s| s == step.name()) {
+                println!("Step {}: completed earlier, skipping", step.name());
+                continue;
+            }
+            if step.probe(&mut sess, &ctx).await? {
+                println!("Step {}: already done, skipping", step.name());
+            } else {
+                println!("------ Step {} ------", step.name());
+                step.run(&mut sess, &ctx)
+                    .await
+                    .with_context(|| format!("step {} failed", step.name()))?;
+            }
+            progress.completed.push(step.name().to_string());
+            progress.save(&ctx.node.name).await?;
+        }
+    }
+    node.hostname = Some(hostname);
+    Ok(())
+}
+
+/// Prepares node and runs `kubeadm init` on it
+pub async fn setup_control_plane(node: &mut NodeState, schedulable: bool) -> anyhow::Result<()> {
+    let mut steps = COMMON_STEPS.to_vec();
+    steps.extend_from_slice(&[Step::PullImages, Step::KubeadmInit, Step::ConfigureKubectl]);
+    if schedulable {
+        steps.push(Step::AllowScheduling);
+    }
+    steps.extend_from_slice(&[Step::AnnotateNode, Step::InstallCilium]);
+    if schedulable {
+        steps.push(Step::RescaleCilium);
+    }
+    steps.push(Step::DownloadKubeconfig);
+    run_steps(node, None, &steps).await
+}
+
+/// Prepares node and joins it to the cluster using token obtained from control plane
+pub async fn setup_worker(control_plane: &NodeState, worker: &mut NodeState) -> anyhow::Result<()> {
+    let mut steps = COMMON_STEPS.to_vec();
+    steps.extend_from_slice(&[Step::KubeadmJoin, Step::AnnotateWorker]);
+    run_steps(worker, Some(control_plane), &steps).await
+}
@@ -0,0 +1,87 @@
+use super::{VmConfig, VmDescription, VmProvider};
+use anyhow::Context as _;
+
+/// Creates VMs in Yandex Cloud using `yc` CLI
+pub struct YandexCloud<'a> {
+    pub config: &'a VmConfig,
+}
+
+impl VmProvider for YandexCloud<'_> {
+    fn create(&self, name: &str) -> anyhow::Result<()> {
+        let config = self.config;
+        let zone = &config.zone;
+        let cores = config.cores.to_string();
+        let core_fraction = config.core_fraction.to_string();
+        let memory = format!("{}g", config.memory_gb);
+        let boot_disk = format!(
+            "image-folder-id={},image-family={},size={}",
+            config.image_folder_id, config.image_family, config.disk_size_gb
+        );
+        let ssh_key = &config.ssh_public_key;
+        let mut cmd = xshell::cmd!(
+            "yc compute instance create 
+        --name {name}
+        --zone {zone}
+        --public-ip
+        --cores {cores}
+        --core-fraction {core_fraction}
+        --memory {memory}
+        --create-boot-disk {boot_disk}
+        --ssh-key {ssh_key}"
+        );
+        if config.preemptible {
+            cmd = cmd.arg("--preemptible");
+        }
+        if let Some(sa) = &config.service_account {
+            cmd = cmd.arg("--service-account-name").arg(sa);
+        }
+        cmd.run()?;
+        Ok(())
+    }
+
+    fn describe(&self, name: &str) -> anyhow::Result<Option<VmDescription>> {
+        if !self.list()?.iter().any(|vm| vm == name) {
+            return Ok(None);
+        }
+        let vm_desc =
+            xshell::cmd!("yc --format json-rest compute instance get --name {name}").read()?;
+        let vm_desc: serde_json::Value = serde_json::from_str(&vm_desc)?;
+
+        Ok(Some(VmDescription {
+            ip: vm_desc
+                .pointer("/networkInterfaces/0/primaryV4Address/oneToOneNat/address")
+                .context("bad pointer or response")?
+                .as_str()
+                .context("wtf not string")?
+                .to_string(),
+            priv_ip: vm_desc
+                .pointer("/networkInterfaces/0/primaryV4Address/address")
+                .context("bad pointer or response")?
+                .as_str()
+                .context("wtf not string")?
+                .to_string(),
+            user: "yc-user".to_string(),
+        }))
+    }
+
+    fn delete(&self, name: &str) -> anyhow::Result<()> {
+        xshell::cmd!("yc compute instance delete --name {name}")
+            .run()
+            .ok();
+        Ok(())
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<String>> {
+        let instances = xshell::cmd!("yc --format json compute instance list").read()?;
+        let instances: Vec<serde_json::Value> = serde_json::from_str(&instances)?;
+        instances
+            .iter()
+            .map(|vm| {
+                vm["name"]
+                    .as_str()
+                    .map(ToString::to_string)
+                    .context("instance name missing")
+            })
+            .collect()
+    }
+}
@@ -1,10 +1,33 @@
 use anyhow::Context as _;
-use kube::Api;
+use futures::StreamExt;
+use kube::{api::ListParams, Api};
+use kube_runtime::watcher::Event;
+use std::{cell::RefCell, future::Future, pin::Pin, rc::Rc, time::Duration};
 
 pub trait Health:
-    k8s_openapi::Resource + Clone + serde::de::DeserializeOwned + kube::api::Meta
+    k8s_openapi::Resource + Clone + serde::de::DeserializeOwned + kube::api::Meta + Send + 'static
 {
+    /// Resources of this kind do not belong to a namespace
+    const CLUSTER_SCOPED: bool = false;
+
     fn is_healthy(&self) -> anyhow::Result<bool>;
+
+    /// Full health check. Kinds whose health depends on other objects
+    /// override this, by default only the object itself is inspected.
+    fn check<'a>(
+        &'a self,
+        _k: &'a kube::Client,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + 'a>> {
+        Box::pin(async move { self.is_healthy() })
+    }
+}
+
+fn make_api<H: Health>(k: &kube::Client, ns: &str) -> Api<H> {
+    if H::CLUSTER_SCOPED {
+        Api::all(k.clone())
+    } else {
+        Api::namespaced(k.clone(), ns)
+    }
 }
 
 impl Health for k8s_openapi::api::apps::v1::Deployment {
@@ -23,48 +46,328 @@ impl Health for k8s_openapi::api::apps::v1::Deployment {
     }
 }
 
-const SLEEP_TIME_SECS: u64 = 3;
+impl Health for k8s_openapi::api::apps::v1::DaemonSet {
+    fn is_healthy(&self) -> anyhow::Result<bool> {
+        let status = self.status.as_ref().context(".status missing")?;
+        let updated = status.updated_number_scheduled.unwrap_or(0);
+        let available = status.number_available.unwrap_or(0);
+        Ok(updated == status.desired_number_scheduled
+            && available == status.desired_number_scheduled)
+    }
+}
 
-pub async fn watch<H: Health>(
-    k: &kube::Client,
-    ns: &str,
-    name: &str,
-    timeout: u64,
-) -> anyhow::Result<()> {
-    println!(
-        "Waiting for {} {}/{} with timeout of {} seconds",
-        H::KIND,
-        ns,
-        name,
-        timeout
-    );
+impl Health for k8s_openapi::api::apps::v1::StatefulSet {
+    fn is_healthy(&self) -> anyhow::Result<bool> {
+        let spec = self.spec.as_ref().context(".spec missing")?;
+        let status = self.status.as_ref().context(".status missing")?;
+        let desired = spec.replicas.unwrap_or(1);
+        Ok(status.ready_replicas.unwrap_or(0) >= desired
+            && status.updated_replicas.unwrap_or(0) >= desired)
+    }
+}
+
+impl Health for k8s_openapi::api::core::v1::Pod {
+    fn is_healthy(&self) -> anyhow::Result<bool> {
+        let status = self.status.as_ref().context(".status missing")?;
+        match status.phase.as_deref() {
+            Some("Succeeded") => return Ok(true),
+            Some("Failed") => anyhow::bail!("Pod failed"),
+            _ => {}
+        }
+        let conditions = match status.conditions.as_ref() {
+            Some(conditions) => conditions,
+            // pod is not scheduled yet
+            None => return Ok(false),
+        };
+        Ok(conditions
+            .iter()
+            .any(|cond| cond.type_ == "Ready" && cond.status == "True"))
+    }
+}
+
+impl Health for k8s_openapi::api::batch::v1::Job {
+    fn is_healthy(&self) -> anyhow::Result<bool> {
+        let spec = self.spec.as_ref().context(".spec missing")?;
+        let status = self.status.as_ref().context(".status missing")?;
+        for cond in status.conditions.iter().flatten() {
+            if cond.type_ == "Failed" && cond.status == "True" {
+                anyhow::bail!(
+                    "Job failed: {}",
+                    cond.message.as_deref().unwrap_or("no message")
+                );
+            }
+        }
+        Ok(status.succeeded.unwrap_or(0) >= spec.completions.unwrap_or(1))
+    }
+}
+
+impl Health for k8s_openapi::api::core::v1::PersistentVolumeClaim {
+    fn is_healthy(&self) -> anyhow::Result<bool> {
+        let status = self.status.as_ref().context(".status missing")?;
+        match status.phase.as_deref() {
+            Some("Bound") => Ok(true),
+            Some("Lost") => anyhow::bail!("Volume of the claim is lost"),
+            _ => Ok(false),
+        }
+    }
+}
+
+impl Health for k8s_openapi::api::core::v1::Service {
+    fn is_healthy(&self) -> anyhow::Result<bool> {
+        let spec = self.spec.as_ref().context(".spec missing")?;
+        if spec.type_.as_deref() == Some("ExternalName") {
+            return Ok(true);
+        }
+        Ok(spec.cluster_ip.is_some())
+    }
+
+    /// Service is healthy when at least one of its endpoints is ready
+    fn check<'a>(
+        &'a self,
+        k: &'a kube::Client,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + 'a>> {
+        Box::pin(async move {
+            if !self.is_healthy()? {
+                return Ok(false);
+            }
+            let spec = self.spec.as_ref().context(".spec missing")?;
+            if spec.type_.as_deref() == Some("ExternalName") {
+                return Ok(true);
+            }
+            let ns = self
+                .metadata
+                .namespace
+                .as_deref()
+                .context(".metadata.namespace missing")?;
+            let name = self
+                .metadata
+                .name
+                .as_deref()
+                .context(".metadata.name missing")?;
+            let endpoints_api =
+                Api::<k8s_openapi::api::core::v1::Endpoints>::namespaced(k.clone(), ns);
+            let endpoints = match endpoints_api.get(name).await {
+                Ok(endpoints) => endpoints,
+                Err(kube::Error::Api(err)) if err.code == 404 => return Ok(false),
+                Err(err) => return Err(err.into()),
+            };
+            Ok(endpoints.subsets.iter().flatten().any(|subset| {
+                subset
+                    .addresses
+                    .as_ref()
+                    .is_some_and(|addresses| !addresses.is_empty())
+            }))
+        })
+    }
+}
+
+impl Health
+    for k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition
+{
+    const CLUSTER_SCOPED: bool = true;
+
+    fn is_healthy(&self) -> anyhow::Result<bool> {
+        let status = match self.status.as_ref() {
+            Some(status) => status,
+            None => return Ok(false),
+        };
+        for cond in status.conditions.iter().flatten() {
+            if cond.type_ == "NamesAccepted" && cond.status == "False" {
+                anyhow::bail!(
+                    "CRD names are not accepted: {}",
+                    cond.message.as_deref().unwrap_or("no message")
+                );
+            }
+            if cond.type_ == "Established" {
+                return Ok(cond.status == "True");
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Health for k8s_openapi::api::core::v1::Node {
+    const CLUSTER_SCOPED: bool = true;
+
+    fn is_healthy(&self) -> anyhow::Result<bool> {
+        let status = self.status.as_ref().context(".status missing")?;
+        let conditions = status
+            .conditions
+            .as_ref()
+            .context(".status.conditions missing")?;
+        for cond in conditions {
+            if cond.type_ == "Ready" {
+                return Ok(cond.status == "True");
+            }
+        }
+        anyhow::bail!("Condition 'Ready' missing");
+    }
+}
 
-    let api = Api::<H>::namespaced(k.clone(), ns);
-    let _initial_state = api
+/// Fetches resource and checks its health once
+pub async fn check_once<H: Health>(k: &kube::Client, ns: &str, name: &str) -> anyhow::Result<bool> {
+    let api = make_api::<H>(k, ns);
+    let state = api
         .get(name)
         .await
         .context("Resource does not exist or is not available")?;
-    let deadline = std::time::Duration::from_secs(timeout);
-    let begin = std::time::Instant::now();
-    loop {
-        if std::time::Instant::now().duration_since(begin) > deadline {
-            anyhow::bail!("Deadline exceeded");
+    state.check(k).await
+}
+
+/// Last observed problem with a resource, `None` once it is healthy
+type Observed = Rc<RefCell<Option<String>>>;
+
+type CheckFn =
+    fn(kube::Client, String, String) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>>>>;
+
+type WaitFn = fn(kube::Client, String, String, Observed) -> Pin<Box<dyn Future<Output = ()>>>;
+
+/// Resource of any `Health` kind, for places which need to check
+/// resources of different kinds together.
+#[derive(Clone)]
+pub struct ResourceRef {
+    pub kind: &'static str,
+    pub namespace: String,
+    pub name: String,
+    check: CheckFn,
+    wait: WaitFn,
+}
+
+impl ResourceRef {
+    pub fn new<H: Health + 'static>(ns: &str, name: &str) -> ResourceRef {
+        ResourceRef {
+            kind: H::KIND,
+            namespace: if H::CLUSTER_SCOPED {
+                String::new()
+            } else {
+                ns.to_string()
+            },
+            name: name.to_string(),
+            check: |k, ns, name| Box::pin(async move { check_once::<H>(&k, &ns, &name).await }),
+            wait: |k, ns, name, observed| Box::pin(wait_healthy::<H>(k, ns, name, observed)),
         }
+    }
 
-        let state = api.get(name).await?;
-        let health = state.is_healthy();
-        match health {
-            Ok(true) => {
-                println!("Resource ready");
-                return Ok(());
-            }
-            Ok(false) => {
-                println!("Resource not ready yet");
+    pub async fn check(&self, k: &kube::Client) -> anyhow::Result<bool> {
+        (self.check)(k.clone(), self.namespace.clone(), self.name.clone()).await
+    }
+}
+
+impl std::fmt::Display for ResourceRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.namespace.is_empty() {
+            write!(f, "{} {}", self.kind, self.name)
+        } else {
+            write!(f, "{} {}/{}", self.kind, self.namespace, self.name)
+        }
+    }
+}
+
+/// Health of some kinds (e.g. Service) depends on other objects, so
+/// resource is re-checked periodically even if it does not change.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+fn observe(observed: &Observed, target: &str, problem: Option<String>) {
+    let mut current = observed.borrow_mut();
+    if *current == problem {
+        return;
+    }
+    match &problem {
+        Some(problem) => println!("{}: {}", target, problem),
+        None => println!("{} is ready", target),
+    }
+    *current = problem;
+}
+
+/// Follows resource until it becomes healthy
+async fn wait_healthy<H: Health>(k: kube::Client, ns: String, name: String, observed: Observed) {
+    let target = ResourceRef::new::<H>(&ns, &name).to_string();
+    let lp = ListParams::default().fields(&format!("metadata.name={}", name));
+    let events = kube_runtime::watcher(make_api::<H>(&k, &ns), lp);
+    futures::pin_mut!(events);
+    let mut last: Option<H> = None;
+    loop {
+        match tokio::time::timeout(RECHECK_INTERVAL, events.next()).await {
+            Ok(Some(Ok(Event::Applied(obj)))) => last = Some(obj),
+            Ok(Some(Ok(Event::Deleted(_)))) => last = None,
+            Ok(Some(Ok(Event::Restarted(objs)))) => last = objs.into_iter().next(),
+            Ok(Some(Err(err))) => {
+                // watcher starts over on the next poll
+                observe(&observed, &target, Some(format!("watch failed: {}", err)));
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
             }
-            Err(err) => {
-                println!("Warning: {:#}", err);
+            Ok(None) => {
+                observe(&observed, &target, Some("watch stream ended".to_string()));
+                futures::future::pending::<()>().await;
             }
+            Err(_elapsed) => {}
+        }
+        let health = match &last {
+            Some(obj) => obj.check(&k).await,
+            None => Err(anyhow::anyhow!("does not exist")),
+        };
+        let problem = match health {
+            Ok(true) => None,
+            Ok(false) => Some("not ready yet".to_string()),
+            Err(err) => Some(format!("{:#}", err)),
+        };
+        let ready = problem.is_none();
+        observe(&observed, &target, problem);
+        if ready {
+            return;
+        }
+    }
+}
+
+/// Waits until all `targets` are healthy. On timeout, reports which of
+/// them were still unhealthy.
+pub async fn wait_all(
+    k: &kube::Client,
+    targets: &[ResourceRef],
+    timeout: u64,
+) -> anyhow::Result<()> {
+    let observed: Vec<Observed> = targets
+        .iter()
+        .map(|_| Rc::new(RefCell::new(Some("no events received".to_string()))))
+        .collect();
+    let waits = targets.iter().zip(&observed).map(|(target, observed)| {
+        (target.wait)(
+            k.clone(),
+            target.namespace.clone(),
+            target.name.clone(),
+            observed.clone(),
+        )
+    });
+    let all = futures::future::join_all(waits);
+    if tokio::time::timeout(Duration::from_secs(timeout), all)
+        .await
+        .is_ok()
+    {
+        return Ok(());
+    }
+    let mut summary = String::new();
+    for (target, observed) in targets.iter().zip(&observed) {
+        if let Some(problem) = &*observed.borrow() {
+            summary.push_str(&format!("\n  {}: {}", target, problem));
         }
-        tokio::time::sleep(std::time::Duration::from_secs(SLEEP_TIME_SECS)).await;
     }
+    anyhow::bail!(
+        "Deadline of {} seconds exceeded, still unhealthy:{}",
+        timeout,
+        summary
+    );
+}
+
+pub async fn watch<H: Health + 'static>(
+    k: &kube::Client,
+    ns: &str,
+    name: &str,
+    timeout: u64,
+) -> anyhow::Result<()> {
+    let target = ResourceRef::new::<H>(ns, name);
+    println!("Waiting for {} with timeout of {} seconds", target, timeout);
+    wait_all(k, &[target], timeout).await
 }
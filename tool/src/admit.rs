@@ -3,13 +3,20 @@ use futures::StreamExt;
 use k8s_openapi::api::core::v1;
 use kube_utils::webhook::Review;
 
+/// Namespaces with this label are not processed by the webhook
+const IGNORE_LABEL: &str = "admission.d-k8s.io/ignore";
+
 pub struct PodReviewer {
     resolver: ImageRegistryResolver,
+    namespaces: NamespaceFilter,
 }
 
 impl PodReviewer {
-    pub fn new(resolver: ImageRegistryResolver) -> Self {
-        PodReviewer { resolver }
+    pub fn new(resolver: ImageRegistryResolver, namespaces: NamespaceFilter) -> Self {
+        PodReviewer {
+            resolver,
+            namespaces,
+        }
     }
 }
 
@@ -64,6 +71,13 @@ impl Review for PodReviewer {
     type Resource = v1::Pod;
 
     fn review(&self, mut pod: Self::Resource) -> anyhow::Result<Self::Resource> {
+        // API server should not send such pods because of namespaceSelector,
+        // but the selector may be changed by hand
+        if let Some(ns) = &pod.metadata.namespace {
+            if self.namespaces.is_excluded(ns) {
+                return Ok(pod);
+            }
+        }
         let should_patch = patch_pod(&mut pod, &Phase::Check) == Some(true);
         if should_patch {
             let repo_addr = self
@@ -104,6 +118,30 @@ fn make_store<K: kube::api::Meta + Clone + Send + Sync + serde::de::DeserializeO
     store
 }
 
+/// Tracks namespaces excluded from admission
+pub struct NamespaceFilter {
+    namespaces: kube_runtime::reflector::Store<v1::Namespace>,
+}
+
+impl NamespaceFilter {
+    pub async fn new() -> anyhow::Result<NamespaceFilter> {
+        let k = kube::Client::try_default().await?;
+        Ok(NamespaceFilter {
+            namespaces: make_store(kube::Api::all(k)),
+        })
+    }
+
+    fn is_excluded(&self, ns: &str) -> bool {
+        let obj_ref = kube_runtime::reflector::ObjectRef::new(ns);
+        self.namespaces.get(&obj_ref).map_or(false, |ns| {
+            ns.metadata
+                .labels
+                .as_ref()
+                .map_or(false, |labels| labels.contains_key(IGNORE_LABEL))
+        })
+    }
+}
+
 impl ImageRegistryResolver {
     pub async fn new() -> anyhow::Result<ImageRegistryResolver> {
         let k = kube::Client::try_default().await?;
@@ -114,24 +152,26 @@ impl ImageRegistryResolver {
     }
 
     fn resolve_svc(&self, ns: &str, name: &str) -> anyhow::Result<String> {
-        let nodes = self.nodes.state();
-        anyhow::ensure!(nodes.len() == 1);
-        let ip = {
-            let n = &nodes[0];
-            let meta = &n.metadata;
-            let anns = meta
-                .annotations
+        // NodePort is exposed on every node, prefer control plane for stable addresses
+        let mut nodes = self.nodes.state();
+        nodes.sort_by_key(|n| {
+            let is_master = n
+                .metadata
+                .labels
                 .as_ref()
-                .context("no annotations on Node object")?;
-            anns.get("d-k8s.io/public-ip")
-                .with_context(|| {
-                    format!(
-                        "annotation 'd-k8s.io/public-ip' missing on node {}",
-                        meta.name.as_deref().unwrap_or_default()
-                    )
-                })?
-                .clone()
-        };
+                .map_or(false, |l| l.contains_key("node-role.kubernetes.io/master"));
+            (!is_master, n.metadata.name.clone())
+        });
+        let ip = nodes
+            .iter()
+            .find_map(|n| {
+                n.metadata
+                    .annotations
+                    .as_ref()
+                    .and_then(|anns| anns.get("d-k8s.io/public-ip"))
+                    .cloned()
+            })
+            .context("annotation 'd-k8s.io/public-ip' missing on all nodes")?;
         let obj_ref = kube_runtime::reflector::ObjectRef::new(name).within(ns);
         let svc = self.services.get(&obj_ref).context("unknown service")?;
         let ports = svc
@@ -1,3 +1,7 @@
+mod object;
+mod selector;
+mod status;
+
 use k8s_openapi::{
     api::core::v1, apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition,
     apimachinery::pkg::apis::meta::v1 as metav1,
@@ -6,13 +10,22 @@ use k8s_openapi::{
 use anyhow::Context as _;
 use futures::StreamExt;
 use kube::{
-    api::{Meta, WatchEvent},
+    api::{ListParams, Meta, PatchParams, PostParams, WatchEvent},
     Api,
 };
 use kube_derive::CustomResource;
 use kube_runtime::watcher::Event;
+use object::DynObject;
+use selector::LabelSelector;
+use status::{PropagationStatus, StatusReporter};
 use tokio_util::sync::CancellationToken;
 
+/// Keeps Propagation until all its copies are deleted
+const FINALIZER: &str = "util.d-k8s.io/cleanup-copies";
+
+/// Delay before failed worker or finalization is retried
+const RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 #[serde(rename_all = "camelCase")]
 struct SourceRef {
@@ -43,33 +56,61 @@ impl SourceRef {
 #[derive(
     CustomResource, Debug, Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
 )]
-#[kube(group = "util.d-k8s.io", version = "v1", kind = "Propagation")]
+#[kube(
+    group = "util.d-k8s.io",
+    version = "v1",
+    kind = "Propagation",
+    status = "PropagationStatus",
+    printcolumn = r#"{"name":"Kind", "type":"string", "jsonPath":".spec.source.kind"}"#,
+    printcolumn = r#"{"name":"Target", "type":"string", "jsonPath":".spec.targetName"}"#,
+    printcolumn = r#"{"name":"Synced", "type":"integer", "jsonPath":".status.syncedNamespaces"}"#,
+    printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
 #[serde(rename_all = "camelCase")]
 struct PropagationSpec {
     /// Reference to object that should be copied
     source: SourceRef,
     /// Name of objects that should be created
     target_name: String,
+    /// Namespaces with matching labels get copies. Without selector all
+    /// namespaces do, unless `includeNamespaces` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace_selector: Option<LabelSelector>,
+    /// Namespaces which get copies regardless of the selector
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include_namespaces: Vec<String>,
+    /// Namespaces which never get copies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exclude_namespaces: Vec<String>,
 }
 
-fn strip_secret(s: &mut v1::Secret) {
-    s.metadata.name = None;
-    s.metadata.namespace = None;
-    s.metadata.creation_timestamp = None;
-    s.metadata.resource_version = None;
-    s.metadata.managed_fields = None;
-    s.metadata.uid = None;
-}
-
-fn cmp_secrets(mut a: v1::Secret, mut b: v1::Secret) -> bool {
-    strip_secret(&mut a);
-    strip_secret(&mut b);
-    if a != b {
-        let ar = serde_json::to_value(&a).unwrap();
-        let br = serde_json::to_value(&b).unwrap();
-        dbg!(json_patch::diff(&ar, &br));
+impl PropagationSpec {
+    /// Checks whether namespace should contain a copy
+    fn wants_copy(&self, ns: &v1::Namespace) -> bool {
+        let name = match &ns.metadata.name {
+            Some(name) => name,
+            None => return false,
+        };
+        // copy would overwrite the source
+        if *name == self.source.namespace && self.target_name == self.source.name {
+            return false;
+        }
+        // terminating namespaces reject new objects
+        if ns.metadata.deletion_timestamp.is_some() {
+            return false;
+        }
+        if self.exclude_namespaces.contains(name) {
+            return false;
+        }
+        if self.include_namespaces.contains(name) {
+            return true;
+        }
+        match &self.namespace_selector {
+            Some(selector) => selector.matches(&ns.metadata.labels.clone().unwrap_or_default()),
+            None => self.include_namespaces.is_empty(),
+        }
     }
-    return a == b;
 }
 
 async fn reconcile_single(
@@ -87,25 +128,25 @@ async fn reconcile_single(
         .source
         .dynamic_resource()
         .within(&propagation.spec.source.namespace)
-        .into_api::<v1::Secret>(k.clone());
+        .into_api::<DynObject>(k.clone());
     let target_api = propagation
         .spec
         .source
         .dynamic_resource()
         .within(ns)
-        .into_api::<v1::Secret>(k.clone());
+        .into_api::<DynObject>(k.clone());
     let mut object = src_api
         .get(&propagation.spec.source.name)
         .await
         .context("failed to fetch")?;
+    object::strip(&mut object);
     object.metadata.namespace = Some(ns.to_string());
-    object.metadata.resource_version = None;
     object.metadata.name = Some(propagation.spec.target_name.clone());
     object.metadata.owner_references = Some(vec![metav1::OwnerReference {
         api_version: "util.d-k8s.io/v1".to_string(),
         block_owner_deletion: Some(false),
         controller: Some(true),
-        kind: "propagation".to_string(),
+        kind: "Propagation".to_string(),
         name: propagation
             .metadata
             .name
@@ -120,24 +161,23 @@ async fn reconcile_single(
 
     match target_api.get(&propagation.spec.target_name).await {
         Ok(existing) => {
-            if cmp_secrets(existing.clone(), object.clone()) {
+            if object::same(&existing, &object) {
                 return Ok(());
             }
             tracing::info!("Copy was changed, replacing");
-            kube_utils::patch_with(
-                k.clone(),
-                |_| async { Ok(object.clone()) },
-                Some(ns),
-                &propagation.spec.target_name,
-            )
-            .await
-            .context("failed to replace a secret")?;
+            // replace is rejected if the copy was changed concurrently,
+            // next event will retry
+            object.metadata.resource_version = existing.metadata.resource_version;
+            target_api
+                .replace(&propagation.spec.target_name, &PostParams::default(), &object)
+                .await
+                .context("failed to replace a copy")?;
         }
         Err(err) => {
             tracing::info!("Copy does not exist ({:#}), creating", err);
 
             target_api
-                .create(&Default::default(), &object)
+                .create(&PostParams::default(), &object)
                 .await
                 .context("failed to create a copy")?;
         }
@@ -145,15 +185,76 @@ async fn reconcile_single(
     Ok(())
 }
 
+/// Deletes copy from the namespace, if it was created by this propagation
+async fn remove_copy(k: &kube::Client, propagation: &Propagation, ns: &str) -> anyhow::Result<()> {
+    let target_api = propagation
+        .spec
+        .source
+        .dynamic_resource()
+        .within(ns)
+        .into_api::<DynObject>(k.clone());
+    let copy = match target_api.get(&propagation.spec.target_name).await {
+        Ok(copy) => copy,
+        Err(kube::Error::Api(err)) if err.code == 404 => return Ok(()),
+        Err(err) => return Err(err).context("failed to fetch a copy"),
+    };
+    let owned = copy
+        .metadata
+        .owner_references
+        .unwrap_or_default()
+        .iter()
+        .any(|owner| Some(&owner.uid) == propagation.metadata.uid.as_ref());
+    if !owned {
+        return Ok(());
+    }
+    tracing::info!(ns = ns, "Namespace is not selected anymore, deleting copy");
+    target_api
+        .delete(&propagation.spec.target_name, &Default::default())
+        .await
+        .context("failed to delete a copy")?;
+    Ok(())
+}
+
+/// Creates, updates or removes copy in the namespace.
+/// Returns whether namespace should have a copy.
+async fn reconcile_namespace(
+    k: &kube::Client,
+    propagation: &Propagation,
+    ns: &v1::Namespace,
+) -> anyhow::Result<bool> {
+    let ns_name = ns
+        .metadata
+        .name
+        .as_ref()
+        .context("missing name in namespace")?;
+    if propagation.spec.wants_copy(ns) {
+        reconcile_single(k, propagation, ns_name).await?;
+        Ok(true)
+    } else {
+        remove_copy(k, propagation, ns_name).await?;
+        Ok(false)
+    }
+}
+
 #[tracing::instrument(skip(k, propagation), fields(propagation = propagation.name().as_str()))]
-async fn watch_for_copies(k: &kube::Client, propagation: &Propagation) -> anyhow::Result<()> {
+async fn watch_for_copies(
+    k: &kube::Client,
+    propagation: &Propagation,
+    status: &StatusReporter,
+) -> anyhow::Result<()> {
     let api = propagation
         .spec
         .source
         .dynamic_resource()
-        .into_api::<v1::Secret>(k.clone());
+        .into_api::<DynObject>(k.clone());
+    // only copies are interesting, not all objects of this kind
+    let lp = ListParams::default().fields(&format!(
+        "metadata.name={}",
+        propagation.spec.target_name
+    ));
+    let ns_api = Api::<v1::Namespace>::all(k.clone());
     let events = api
-        .watch(&Default::default(), "0")
+        .watch(&lp, "0")
         .await
         .context("failed to start watch")?;
     tokio::pin!(events);
@@ -166,10 +267,21 @@ async fn watch_for_copies(k: &kube::Client, propagation: &Propagation) -> anyhow
                     .namespace
                     .as_ref()
                     .context("missing name in namespace")?;
-                if let Err(e) = reconcile_single(k, propagation, ns).await {
+                let res = async {
+                    let ns = match ns_api.get(ns).await {
+                        Ok(ns) => ns,
+                        // namespace is gone together with the copy
+                        Err(kube::Error::Api(err)) if err.code == 404 => return Ok(false),
+                        Err(err) => return Err(err).context("failed to fetch namespace"),
+                    };
+                    reconcile_namespace(k, propagation, &ns).await
+                }
+                .await;
+                status.record(ns, &res);
+                if let Err(e) = res {
                     tracing::warn!(
                         namespace = ns.as_str(),
-                        "Failed to procecc a changed copy: {:#}",
+                        "Failed to process a changed copy: {:#}",
                         e
                     );
                 }
@@ -182,7 +294,11 @@ async fn watch_for_copies(k: &kube::Client, propagation: &Propagation) -> anyhow
 }
 
 #[tracing::instrument(skip(k, propagation), fields(propagation = propagation.name().as_str()))]
-async fn watch_for_namespaces(k: &kube::Client, propagation: &Propagation) -> anyhow::Result<()> {
+async fn watch_for_namespaces(
+    k: &kube::Client,
+    propagation: &Propagation,
+    status: &StatusReporter,
+) -> anyhow::Result<()> {
     let ns_api = Api::<v1::Namespace>::all(k.clone());
     let events = ns_api
         .watch(&Default::default(), "0")
@@ -192,20 +308,199 @@ async fn watch_for_namespaces(k: &kube::Client, propagation: &Propagation) -> an
     while let Some(ev) = events.next().await {
         let ev = ev.context("watch error")?;
         match ev {
-            WatchEvent::Added(ns) => {
-                let ns_name = ns
-                    .metadata
-                    .name
-                    .as_ref()
-                    .context("missing name in namespace")?;
-                if let Err(e) = reconcile_single(k, propagation, ns_name).await {
+            // labels may change, so that namespace starts or stops matching
+            WatchEvent::Added(ns) | WatchEvent::Modified(ns) => {
+                let ns_name = ns.metadata.name.as_deref().unwrap_or_default();
+                let res = reconcile_namespace(k, propagation, &ns).await;
+                status.record(ns_name, &res);
+                if let Err(e) = res {
                     tracing::warn!(
-                        namespace = ns_name.as_str(),
-                        "Failed to process a new namespace: {:#}",
+                        namespace = ns_name,
+                        "Failed to process a namespace: {:#}",
                         e
                     );
                 }
             }
+            WatchEvent::Deleted(ns) => {
+                status.forget(ns.metadata.name.as_deref().unwrap_or_default());
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn is_owned_by(object: &DynObject, propagation: &Propagation) -> bool {
+    let uid = match &propagation.metadata.uid {
+        Some(uid) => uid,
+        None => return false,
+    };
+    object
+        .metadata
+        .owner_references
+        .iter()
+        .flatten()
+        .any(|owner| owner.uid == *uid)
+}
+
+/// Deletes copies created by the propagation in all namespaces, except
+/// ones named `keep`
+async fn delete_copies(
+    k: &kube::Client,
+    propagation: &Propagation,
+    keep: Option<&str>,
+) -> anyhow::Result<()> {
+    let source = &propagation.spec.source;
+    let api = source.dynamic_resource().into_api::<DynObject>(k.clone());
+    let objects = api
+        .list(&ListParams::default())
+        .await
+        .context("failed to list copies")?;
+    for object in objects {
+        let name = object.metadata.name.as_deref().unwrap_or_default();
+        if !is_owned_by(&object, propagation) || Some(name) == keep {
+            continue;
+        }
+        let ns = object
+            .metadata
+            .namespace
+            .as_deref()
+            .context("copy is not namespaced")?;
+        tracing::info!(ns = ns, name = name, "Deleting copy");
+        let ns_api = source
+            .dynamic_resource()
+            .within(ns)
+            .into_api::<DynObject>(k.clone());
+        match ns_api.delete(name, &Default::default()).await {
+            Ok(_) => (),
+            Err(kube::Error::Api(err)) if err.code == 404 => (),
+            Err(err) => return Err(err).context("failed to delete a copy"),
+        }
+    }
+    Ok(())
+}
+
+fn has_finalizer(propagation: &Propagation) -> bool {
+    propagation
+        .metadata
+        .finalizers
+        .iter()
+        .flatten()
+        .any(|finalizer| finalizer == FINALIZER)
+}
+
+/// Reads current state of the propagation, `None` if it is gone.
+/// Objects from watch events may be stale.
+async fn get_propagation(k: &kube::Client, name: &str) -> anyhow::Result<Option<Propagation>> {
+    match Api::<Propagation>::all(k.clone()).get(name).await {
+        Ok(propagation) => Ok(Some(propagation)),
+        Err(kube::Error::Api(err)) if err.code == 404 => Ok(None),
+        Err(err) => Err(err).context("failed to fetch propagation"),
+    }
+}
+
+/// Replaces finalizers of the propagation. Fails if propagation was
+/// changed since it was read.
+async fn set_finalizers(
+    k: &kube::Client,
+    propagation: &Propagation,
+    finalizers: Vec<String>,
+) -> anyhow::Result<()> {
+    let api = Api::<Propagation>::all(k.clone());
+    // merge patch replaces lists, resourceVersion protects from
+    // overwriting concurrent changes
+    let patch = serde_json::json!({
+        "metadata": {
+            "finalizers": finalizers,
+            "resourceVersion": propagation.metadata.resource_version,
+        }
+    });
+    api.patch(
+        &propagation.name(),
+        &PatchParams::default(),
+        serde_json::to_vec(&patch)?,
+    )
+    .await
+    .context("failed to update finalizers")?;
+    Ok(())
+}
+
+async fn ensure_finalizer(k: &kube::Client, name: &str) -> anyhow::Result<()> {
+    let propagation = match get_propagation(k, name).await? {
+        Some(propagation) => propagation,
+        None => return Ok(()),
+    };
+    // terminating objects can not get new finalizers
+    if has_finalizer(&propagation) || propagation.metadata.deletion_timestamp.is_some() {
+        return Ok(());
+    }
+    let mut finalizers = propagation.metadata.finalizers.clone().unwrap_or_default();
+    finalizers.push(FINALIZER.to_string());
+    set_finalizers(k, &propagation, finalizers).await
+}
+
+/// Deletes all copies and lets API server delete the propagation
+async fn finalize(k: &kube::Client, name: &str) -> anyhow::Result<()> {
+    let propagation = match get_propagation(k, name).await? {
+        Some(propagation) => propagation,
+        None => return Ok(()),
+    };
+    if !has_finalizer(&propagation) {
+        return Ok(());
+    }
+    delete_copies(k, &propagation, None).await?;
+    let finalizers = propagation
+        .metadata
+        .finalizers
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|finalizer| finalizer != FINALIZER)
+        .collect();
+    set_finalizers(k, &propagation, finalizers).await
+}
+
+/// Reconciles all namespaces when the source object changes
+#[tracing::instrument(skip(k, propagation, status), fields(propagation = propagation.name().as_str()))]
+async fn watch_for_source(
+    k: &kube::Client,
+    propagation: &Propagation,
+    status: &StatusReporter,
+) -> anyhow::Result<()> {
+    let source = &propagation.spec.source;
+    let api = source
+        .dynamic_resource()
+        .within(&source.namespace)
+        .into_api::<DynObject>(k.clone());
+    let lp = ListParams::default().fields(&format!("metadata.name={}", source.name));
+    let ns_api = Api::<v1::Namespace>::all(k.clone());
+    let events = api
+        .watch(&lp, "0")
+        .await
+        .context("failed to start watch")?;
+    tokio::pin!(events);
+    while let Some(ev) = events.next().await {
+        let ev = ev.context("watch error")?;
+        match ev {
+            WatchEvent::Added(_) | WatchEvent::Modified(_) => {
+                tracing::info!("Source was changed, updating copies");
+                let namespaces = ns_api
+                    .list(&ListParams::default())
+                    .await
+                    .context("failed to list namespaces")?;
+                for ns in namespaces {
+                    let ns_name = ns.metadata.name.as_deref().unwrap_or_default();
+                    let res = reconcile_namespace(k, propagation, &ns).await;
+                    status.record(ns_name, &res);
+                    if let Err(e) = res {
+                        tracing::warn!(
+                            namespace = ns_name,
+                            "Failed to update a copy: {:#}",
+                            e
+                        );
+                    }
+                }
+            }
             _ => (),
         }
     }
@@ -213,23 +508,36 @@ async fn watch_for_namespaces(k: &kube::Client, propagation: &Propagation) -> an
 }
 
 /// Watches single propagation.
-/// This function does not handle propagation updates.
+/// Supervisor restarts it when the spec changes.
 async fn watch_propagation(
     k: &kube::Client,
     propagation: &Propagation,
     cancel: CancellationToken,
 ) -> anyhow::Result<()> {
-    let watch_copies = watch_for_copies(k, propagation);
-    let watch_namespaces = watch_for_namespaces(k, propagation);
+    ensure_finalizer(k, &propagation.name()).await?;
+    // copies with previous `targetName`
+    delete_copies(k, propagation, Some(&propagation.spec.target_name)).await?;
+    let status = StatusReporter::new();
+    let watch_copies = watch_for_copies(k, propagation, &status);
+    let watch_namespaces = watch_for_namespaces(k, propagation, &status);
+    let watch_source = watch_for_source(k, propagation, &status);
+    let report_status = status.run(k, propagation);
     tokio::select! {
         res = watch_copies => res,
         res = watch_namespaces => res,
+        res = watch_source => res,
+        res = report_status => res,
         _ = cancel.cancelled() => anyhow::bail!("Cancelled")
     }
 }
 
 struct Worker {
     propagation_name: String,
+    /// Identifies version of the propagation the worker was started for
+    uid: Option<String>,
+    generation: Option<i64>,
+    /// Worker deletes copies of terminating propagation
+    finalizing: bool,
     cancel: CancellationToken,
 }
 
@@ -252,12 +560,87 @@ impl Supervisor {
         }
     }
 
+    /// Stops watching the propagation and cleans up after it
+    fn finalize(&mut self, propagation: &Propagation) {
+        if let Some(idx) = self.find_worker(propagation) {
+            if self.workers[idx].finalizing {
+                return;
+            }
+        }
+        self.untrack(propagation);
+        let cancel = CancellationToken::new();
+        let name = propagation.name();
+        self.workers.push(Worker {
+            cancel: cancel.clone(),
+            propagation_name: name.clone(),
+            uid: propagation.metadata.uid.clone(),
+            generation: propagation.metadata.generation,
+            finalizing: true,
+        });
+        let k = self.k.clone();
+        tokio::task::spawn(async move {
+            while let Err(err) = finalize(&k, &name).await {
+                tracing::warn!(
+                    propagation = name.as_str(),
+                    "Failed to finalize propagation: {:#}",
+                    err
+                );
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = tokio::time::sleep(RETRY_INTERVAL) => (),
+                }
+            }
+        });
+    }
+
+    /// Starts processing of a new or updated propagation
+    fn handle(&mut self, propagation: &Propagation) {
+        if propagation.metadata.deletion_timestamp.is_some() {
+            self.finalize(propagation);
+        } else {
+            self.track(propagation);
+        }
+    }
+
+    /// Stops workers of propagations which were deleted while watch
+    /// was not running
+    fn retain(&mut self, propagations: &[Propagation]) {
+        self.workers.retain(|worker| {
+            let exists = propagations
+                .iter()
+                .any(|prop| prop.metadata.name.as_ref() == Some(&worker.propagation_name));
+            if !exists {
+                worker.cancel.cancel();
+            }
+            exists
+        });
+    }
+
+    /// Checks whether running worker is started for the same spec.
+    /// Status and metadata updates do not change generation.
+    fn is_up_to_date(&self, propagation: &Propagation) -> bool {
+        let worker = match self.find_worker(propagation) {
+            Some(idx) => &self.workers[idx],
+            None => return false,
+        };
+        !worker.finalizing
+            && worker.uid == propagation.metadata.uid
+            && worker.generation == propagation.metadata.generation
+            && has_finalizer(propagation)
+    }
+
     fn track(&mut self, propagation: &Propagation) {
+        if self.is_up_to_date(propagation) {
+            return;
+        }
         self.untrack(propagation);
         let cancel = CancellationToken::new();
         self.workers.push(Worker {
             cancel: cancel.clone(),
             propagation_name: propagation.metadata.name.clone().expect("name missing"),
+            uid: propagation.metadata.uid.clone(),
+            generation: propagation.metadata.generation,
+            finalizing: false,
         });
         let k = self.k.clone();
         let propagation = propagation.clone();
@@ -268,13 +651,17 @@ impl Supervisor {
                 }
                 if let Err(err) = watch_propagation(&k, &propagation, cancel.clone()).await {
                     tracing::warn!("Propagation reconciler failed: {:#}", err);
+                    tokio::select! {
+                        _ = cancel.cancelled() => break,
+                        _ = tokio::time::sleep(RETRY_INTERVAL) => (),
+                    }
                 }
             }
         });
     }
 }
 
-/// Ensures that `local-registry-credentials` secret is available in all namespaces
+/// Copies objects of any kind to all namespaces, as requested by Propagations
 pub async fn copy_to_ns_controller(k: &kube::Client) {
     let propagations_api = Api::<Propagation>::all(k.clone());
     let propagations_watch = kube_runtime::watcher(propagations_api, Default::default());
@@ -290,15 +677,16 @@ pub async fn copy_to_ns_controller(k: &kube::Client) {
         match item {
             Ok(ev) => match ev {
                 Event::Applied(prop) => {
-                    sv.track(&prop);
+                    sv.handle(&prop);
                 }
+                // copies were already deleted by the finalizer
                 Event::Deleted(prop) => {
                     sv.untrack(&prop);
-                    tracing::error!("TODO: Proper deletion of Propagations is not supported, copies will be leaked")
                 }
                 Event::Restarted(props) => {
+                    sv.retain(&props);
                     for prop in props {
-                        sv.track(&prop);
+                        sv.handle(&prop);
                     }
                 }
             },
@@ -0,0 +1,94 @@
+//! Objects of kinds which are only known at runtime
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+/// Source object or its copy; kind is given by the Propagation
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DynObject {
+    #[serde(rename = "apiVersion")]
+    pub api_version: String,
+    pub kind: String,
+    #[serde(default)]
+    pub metadata: ObjectMeta,
+    #[serde(flatten)]
+    pub data: serde_json::Map<String, serde_json::Value>,
+}
+
+// requests are built with `SourceRef::dynamic_resource`, so these are
+// never used
+impl k8s_openapi::Resource for DynObject {
+    const API_VERSION: &'static str = "";
+    const GROUP: &'static str = "";
+    const KIND: &'static str = "";
+    const VERSION: &'static str = "";
+}
+
+impl k8s_openapi::Metadata for DynObject {
+    type Ty = ObjectMeta;
+
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn metadata_mut(&mut self) -> &mut ObjectMeta {
+        &mut self.metadata
+    }
+}
+
+/// Removes fields which are populated by the API server or controllers,
+/// so that object can be created in other namespace or compared with
+/// its copy
+pub fn strip(object: &mut DynObject) {
+    let meta = &mut object.metadata;
+    meta.uid = None;
+    meta.resource_version = None;
+    meta.generation = None;
+    meta.creation_timestamp = None;
+    meta.deletion_timestamp = None;
+    meta.deletion_grace_period_seconds = None;
+    meta.managed_fields = None;
+    meta.self_link = None;
+    object.data.remove("status");
+    match object.kind.as_str() {
+        // token controller adds secrets of the account
+        "ServiceAccount" => {
+            object.data.remove("secrets");
+        }
+        // cluster IPs and node ports are allocated for each service and
+        // can not be shared
+        "Service" => {
+            if let Some(spec) = object
+                .data
+                .get_mut("spec")
+                .and_then(|spec| spec.as_object_mut())
+            {
+                spec.remove("clusterIP");
+                spec.remove("clusterIPs");
+                spec.remove("healthCheckNodePort");
+                let ports = spec
+                    .get_mut("ports")
+                    .and_then(|ports| ports.as_array_mut());
+                for port in ports.into_iter().flatten() {
+                    if let Some(port) = port.as_object_mut() {
+                        port.remove("nodePort");
+                    }
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Checks that objects are equal, ignoring server-populated fields
+pub fn same(a: &DynObject, b: &DynObject) -> bool {
+    let mut a = a.clone();
+    let mut b = b.clone();
+    strip(&mut a);
+    strip(&mut b);
+    let a = serde_json::to_value(&a).expect("object is serializable");
+    let b = serde_json::to_value(&b).expect("object is serializable");
+    if a != b {
+        tracing::debug!("copy differs: {:?}", json_patch::diff(&a, &b));
+        return false;
+    }
+    true
+}
@@ -0,0 +1,181 @@
+//! Status of Propagation: which namespaces have up-to-date copies
+use super::Propagation;
+use anyhow::Context as _;
+use kube::api::{Meta, PatchParams};
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+/// Status is written at most this often, so that bursts of events
+/// (e.g. initial list of namespaces) result in a single update
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(
+    Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PropagationStatus {
+    /// Generation of the spec which status corresponds to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    /// Number of namespaces which have up-to-date copy
+    #[serde(default)]
+    pub synced_namespaces: u32,
+    /// Namespaces where copy could not be created or updated
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_namespaces: Vec<FailedNamespace>,
+}
+
+#[derive(
+    Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    /// Only `Ready` is used
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// `True`, `False` or `Unknown`
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    pub last_transition_time: String,
+}
+
+#[derive(
+    Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, schemars::JsonSchema,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedNamespace {
+    pub namespace: String,
+    /// Last error
+    pub error: String,
+}
+
+/// Collects results of reconciliation and writes them to status
+pub struct StatusReporter {
+    /// Namespaces which should have a copy; value is the last error
+    results: Mutex<BTreeMap<String, Option<String>>>,
+    /// Last written status
+    written: Mutex<Option<PropagationStatus>>,
+}
+
+impl StatusReporter {
+    pub fn new() -> StatusReporter {
+        StatusReporter {
+            results: Mutex::new(BTreeMap::new()),
+            written: Mutex::new(None),
+        }
+    }
+
+    /// Records outcome for the namespace. `Ok(false)` means namespace
+    /// should not have a copy.
+    pub fn record(&self, ns: &str, res: &anyhow::Result<bool>) {
+        let mut results = self.results.lock().unwrap();
+        match res {
+            Ok(true) => {
+                results.insert(ns.to_string(), None);
+            }
+            Ok(false) => {
+                results.remove(ns);
+            }
+            Err(err) => {
+                results.insert(ns.to_string(), Some(format!("{:#}", err)));
+            }
+        }
+    }
+
+    pub fn forget(&self, ns: &str) {
+        self.results.lock().unwrap().remove(ns);
+    }
+
+    fn compute(&self, propagation: &Propagation) -> PropagationStatus {
+        let results = self.results.lock().unwrap();
+        let failed_namespaces: Vec<_> = results
+            .iter()
+            .filter_map(|(ns, error)| {
+                error.as_ref().map(|error| FailedNamespace {
+                    namespace: ns.clone(),
+                    error: error.clone(),
+                })
+            })
+            .collect();
+        let synced_namespaces = (results.len() - failed_namespaces.len()) as u32;
+        let (status, reason, message) = if failed_namespaces.is_empty() {
+            (
+                "True",
+                "Synced",
+                format!("copies are up to date in {} namespaces", synced_namespaces),
+            )
+        } else {
+            (
+                "False",
+                "SyncFailed",
+                format!("failed to sync {} namespaces", failed_namespaces.len()),
+            )
+        };
+        let previous = self
+            .written
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|written| {
+                written
+                    .conditions
+                    .iter()
+                    .find(|condition| condition.type_ == "Ready")
+                    .cloned()
+            });
+        let last_transition_time = match previous {
+            Some(previous) if previous.status == status => previous.last_transition_time,
+            _ => chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        };
+        PropagationStatus {
+            observed_generation: propagation.metadata.generation,
+            conditions: vec![Condition {
+                type_: "Ready".to_string(),
+                status: status.to_string(),
+                reason: reason.to_string(),
+                message,
+                last_transition_time,
+            }],
+            synced_namespaces,
+            failed_namespaces,
+        }
+    }
+
+    async fn flush(&self, k: &kube::Client, propagation: &Propagation) -> anyhow::Result<()> {
+        let api = kube::Api::<Propagation>::all(k.clone());
+        if self.written.lock().unwrap().is_none() {
+            // status from the event which started the worker may be stale
+            let live = api
+                .get(&propagation.name())
+                .await
+                .context("failed to fetch propagation")?;
+            *self.written.lock().unwrap() = live.status;
+        }
+        let status = self.compute(propagation);
+        if self.written.lock().unwrap().as_ref() == Some(&status) {
+            return Ok(());
+        }
+        let patch = serde_json::json!({ "status": status });
+        api.patch_status(
+            &propagation.name(),
+            &PatchParams::default(),
+            serde_json::to_vec(&patch)?,
+        )
+        .await
+        .context("failed to update status")?;
+        *self.written.lock().unwrap() = Some(status);
+        Ok(())
+    }
+
+    /// Writes status when it changes. Never returns successfully.
+    pub async fn run(&self, k: &kube::Client, propagation: &Propagation) -> anyhow::Result<()> {
+        loop {
+            tokio::time::sleep(FLUSH_INTERVAL).await;
+            if let Err(err) = self.flush(k, propagation).await {
+                tracing::warn!("{:#}", err);
+            }
+        }
+    }
+}
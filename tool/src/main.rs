@@ -48,8 +48,9 @@ async fn try_rocket() -> anyhow::Result<rocket::Rocket> {
 
 async fn make_server() -> anyhow::Result<Server> {
     let resolver = admit::ImageRegistryResolver::new().await?;
+    let namespaces = admit::NamespaceFilter::new().await?;
     let mut server = Server::builder();
-    server.add_reviewer(admit::PodReviewer::new(resolver));
+    server.add_reviewer(admit::PodReviewer::new(resolver, namespaces));
     Ok(server.build())
 }
 